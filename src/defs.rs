@@ -0,0 +1,195 @@
+//! Haystack 4 def namespace.
+//!
+//! A `Namespace` holds the `is` inheritance of each def, ie `ahu` is an `airHandlingEquip` which
+//! is an `equip`. Filters can consult it so a query for `equip` also matches entities tagged `ahu`.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use crate::error::*;
+use crate::token::*;
+use crate::zinc_tokenizer::grid;
+
+#[derive(Debug, Clone, Default)]
+pub struct Namespace {
+    // def name -> direct supertypes from its is tag
+    defs: HashMap<String, Vec<String>>,
+    // def name -> every def it inherits from, including itself
+    inheritance: HashMap<String, HashSet<String>>,
+    // the conjunct defs, ie elec-meter
+    conjuncts: Vec<String>,
+}
+
+/// Returns the parts of a conjunct def name, ie `elec-meter` is `["elec", "meter"]`.
+pub fn conjunct_parts(name: &str) -> Vec<&str> {
+    name.split('-').collect()
+}
+
+fn symbol_name(val: &Val) -> Option<String> {
+    match val.cast_to_type::<Token>()? {
+        Token::Symbol(name) => Some(name),
+        _ => None,
+    }
+}
+
+fn col_index(grid: &Grid, name: &str) -> Option<usize> {
+    (0..grid.cols.len()).find(|&i| grid.cols[i].get_id_as_str().as_deref() == Some(name))
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Namespace::default()
+    }
+
+    // Works out the inheritance of every def once, rather than on every lookup
+    fn rebuild(&mut self) {
+        let inheritance = self.defs.keys().map(|name| (name.clone(), self.walk_inheritance(name))).collect();
+
+        self.inheritance = inheritance;
+        self.conjuncts = self.defs.keys().filter(|d| d.contains('-')).cloned().collect();
+    }
+
+    fn walk_inheritance(&self, name: &str) -> HashSet<String> {
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut todo: Vec<String> = vec![name.to_string()];
+
+        while let Some(n) = todo.pop() {
+            if seen.insert(n.clone()) {
+                todo.extend(self.supertypes(&n));
+            }
+        }
+
+        seen
+    }
+
+    /// Builds a namespace from a defs grid which must have `def` and `is` columns.
+    pub fn from_grid(defs_grid: &Grid) -> HaystackResult<Namespace> {
+        let def_col = col_index(defs_grid, "def").ok_or("Defs grid has no def column")?;
+        let is_col = col_index(defs_grid, "is").ok_or("Defs grid has no is column")?;
+
+        let mut ns = Namespace::new();
+
+        for row in defs_grid.rows.clone() {
+            let name = match symbol_name(&row[def_col]) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let is: Vec<String> = match row[is_col].cast_to_type_ref::<List>() {
+                Some(list) => list.cast_to_type_ref::<Token>().unwrap_or_default().iter()
+                    .filter_map(|t| match t {
+                        Token::Symbol(s) => Some(s.to_string()),
+                        _ => None,
+                    }).collect(),
+                None => symbol_name(&row[is_col]).into_iter().collect(),
+            };
+
+            ns.defs.insert(name, is);
+        }
+
+        ns.rebuild();
+
+        Ok(ns)
+    }
+
+    pub fn from_zinc(s: &str) -> HaystackResult<Namespace> {
+        let (_, defs_grid) = grid(s).map_err(|_| HaystackError::GeneralError("Unable to parse defs grid".to_string()))?;
+        Namespace::from_grid(&defs_grid)
+    }
+
+    /// Loads a namespace from a zinc file such as `defs.zinc`.
+    pub fn load(path: &str) -> HaystackResult<Namespace> {
+        let s = fs::read_to_string(path)?;
+        Namespace::from_zinc(&s)
+    }
+
+    pub fn add_def(&mut self, name: &str, is: &[&str]) {
+        self.defs.insert(name.to_string(), is.iter().map(|s| s.to_string()).collect());
+        self.rebuild();
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.defs.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.defs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defs.is_empty()
+    }
+
+    /// The direct supertypes of a def.
+    pub fn supertypes(&self, name: &str) -> Vec<String> {
+        self.defs.get(name).cloned().unwrap_or_default()
+    }
+
+    /// All defs that `name` inherits from, including itself.
+    pub fn inheritance(&self, name: &str) -> HashSet<String> {
+        match self.inheritance.get(name) {
+            Some(inherited) => inherited.clone(),
+            None => std::iter::once(name.to_string()).collect(),
+        }
+    }
+
+    pub fn is_subtype(&self, name: &str, of: &str) -> bool {
+        name == of || self.inheritance.get(name).is_some_and(|inherited| inherited.contains(of))
+    }
+
+    /// Returns true if an entity with `tags` implements the def `name`.
+    ///
+    /// A tag matches if its def is `name` or inherits from it. Conjunct defs like `elec-meter`
+    /// match when the entity has every part of the conjunct.
+    pub fn fits(&self, tags: &[Tag], name: &str) -> bool {
+        let tag_names: HashSet<String> = tags.iter().map(|t| t.get_id()).collect();
+
+        let has_conjunct = |conjunct: &str| conjunct_parts(conjunct).iter().all(|p| tag_names.contains(*p));
+
+        if name.contains('-') && has_conjunct(name) {
+            return true;
+        }
+
+        if tag_names.iter().any(|t| self.is_subtype(t, name)) {
+            return true;
+        }
+
+        self.conjuncts.iter().any(|d| has_conjunct(d) && self.is_subtype(d, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_test() {
+        let ns = Namespace::load("test_files/defs.zinc").unwrap();
+
+        assert!(ns.contains("ahu"));
+        assert_eq!(ns.supertypes("ahu"), vec!["airHandlingEquip".to_string()]);
+        assert!(ns.is_subtype("ahu", "equip"));
+        assert!(!ns.is_subtype("equip", "ahu"));
+        assert!(ns.is_subtype("ac-elec-meter", "elec-meter"));
+
+        let ahu = vec![Tag::new_marker("ahu"), Tag::new_marker("equip")];
+        assert!(ns.fits(&ahu, "ahu"));
+        assert!(ns.fits(&ahu, "airHandlingEquip"));
+        assert!(!ns.fits(&ahu, "meter"));
+
+        let rtu = vec![Tag::new_marker("rtu"), Tag::new_marker("equip")];
+        assert!(ns.fits(&rtu, "ahu"));
+
+        let meter = vec![Tag::new_marker("elec"), Tag::new_marker("meter")];
+        assert!(ns.fits(&meter, "elec-meter"));
+        assert!(ns.fits(&meter, "meter"));
+        assert!(!ns.fits(&meter, "ac-elec-meter"));
+
+        // Adding a def updates the inheritance of the defs below it
+        let mut ns = Namespace::new();
+        ns.add_def("ahu", &["airHandlingEquip"]);
+        assert!(!ns.is_subtype("ahu", "equip"));
+        ns.add_def("airHandlingEquip", &["equip"]);
+        assert!(ns.is_subtype("ahu", "equip"));
+        assert_eq!(ns.inheritance("nothing").len(), 1);
+    }
+}
@@ -14,6 +14,30 @@ use std::num;
 
 
 
+/// A range of chars in a filter string, `start` inclusive and `end` exclusive, counted from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The column the span starts at, counted from 1 as an editor would.
+    pub fn column(&self) -> usize {
+        self.start + 1
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}", self.column())
+    }
+}
+
 /// An error produced by the shunting-yard algorightm.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RPNError {
@@ -72,6 +96,8 @@ pub enum FilterError {
     RPNError(RPNError),
     // A catch all for all other errors during evaluation
     EvalError(String),
+    /// A numeric comparison between units that cannot be converted, ie `°F` and `kW`.
+    IncompatibleUnits(String, String),
 }
 
 impl fmt::Display for FilterError {
@@ -95,6 +121,19 @@ impl fmt::Display for FilterError {
                 write!(f, "Eval error: ")?;
                 e.fmt(f)
             }
+            FilterError::IncompatibleUnits(ref a, ref b) => {
+                write!(f, "Evaluation error: cannot compare `{}` with `{}`.", a, b)
+            }
+        }
+    }
+}
+
+impl FilterError {
+    /// Where in the filter string the error is, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            FilterError::ParseError(e) => e.span(),
+            _ => None,
         }
     }
 }
@@ -117,6 +156,7 @@ impl std::error::Error for FilterError {
             FilterError::UnknownVariable(_) => "unknown variable",
             FilterError::UnknownAlias(_) => "unknown alias",
             FilterError::EvalError(_) => "eval error",
+            FilterError::IncompatibleUnits(_, _) => "incompatible units",
             FilterError::ParseError(ref e) => e.description(),
             FilterError::RPNError(ref e) => e.description(),
         }
@@ -145,10 +185,24 @@ pub enum FilterTokenParseError {
     MissingRParen(i32),
     /// Missing operator or function argument at the end of the expression.
     MissingArgument,
+    /// Something else was expected at the span, ie a value after `==`.
+    Expected(String, Span),
+    /// The text at the span is not allowed there.
+    Unexpected(String, Span),
 
     UnknownFilterTokenParseError
 }
 
+impl FilterTokenParseError {
+    /// Where in the filter string the error is, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            FilterTokenParseError::Expected(_, span) | FilterTokenParseError::Unexpected(_, span) => Some(*span),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for FilterTokenParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &*self {
@@ -161,6 +215,8 @@ impl fmt::Display for FilterTokenParseError {
                 if *i == 1 { "is" } else { "es" }
             ),
             FilterTokenParseError::MissingArgument => write!(f, "Missing argument at the end of expression."),
+            FilterTokenParseError::Expected(s, span) => write!(f, "Expected {} at {}.", s, span),
+            FilterTokenParseError::Unexpected(s, span) => write!(f, "Unexpected {} at {}.", s, span),
             FilterTokenParseError::UnknownFilterTokenParseError => write!(f, "Unknown filter pass error."),
         }
     }
@@ -173,6 +229,8 @@ impl std::error::Error for FilterTokenParseError {
             FilterTokenParseError::UnexpectedStrToken(_) => "Unexpected token",
             FilterTokenParseError::MissingRParen(_) => "missing right parenthesis",
             FilterTokenParseError::MissingArgument => "missing argument",
+            FilterTokenParseError::Expected(_, _) => "expected token",
+            FilterTokenParseError::Unexpected(_, _) => "unexpected token",
             FilterTokenParseError::UnknownFilterTokenParseError => "unknown filter error",
         }
     }
@@ -185,6 +243,11 @@ impl std::error::Error for FilterTokenParseError {
 pub enum HaystackError {
     AuthError,
     GeneralError(String),
+    /// The op is not implemented by the backend.
+    NotSupported(String),
+    /// No entity has the id.
+    UnknownRecord(String),
+    Filter(FilterError),
     //ParseError(String),
     Io(std::io::Error),
     // ParseInt(std::num::ParseIntError),
@@ -193,7 +256,7 @@ pub enum HaystackError {
     //PostgresError(error: String),
     // NotFound,
     //ModBusError(modbus::Error),
-    //SerdeError(serde_json::Error),
+    SerdeError(serde_json::Error),
 }
 
 pub type HaystackResult<T> = std::result::Result<T, HaystackError>;
@@ -203,6 +266,9 @@ impl fmt::Display for HaystackError {
         match *self {
             HaystackError::AuthError => "HaystackAuthError".to_string().fmt(f),
             HaystackError::GeneralError(ref err) => err.fmt(f),
+            HaystackError::NotSupported(ref op) => write!(f, "{} is not supported", op),
+            HaystackError::UnknownRecord(ref id) => write!(f, "Unknown record {}", id),
+            HaystackError::Filter(ref err) => err.fmt(f),
             //HaystackError::ParseError(ref err) => err.fmt(f),
             HaystackError::Io(ref err) => err.fmt(f),
             // HaystackError::ParseInt(ref err) => err.fmt(f),
@@ -211,7 +277,7 @@ impl fmt::Display for HaystackError {
             // HaystackError::NotFound => write!(f, "No matching cities with a \
             //                                 population were found."),
             //HaystackError::ModBusError(ref err) => err.fmt(f),
-            //HaystackError::SerdeError(ref err) => err.fmt(f),
+            HaystackError::SerdeError(ref err) => err.fmt(f),
         }
     }
 }
@@ -235,6 +301,12 @@ impl From<&str> for HaystackError {
     }
 }
 
+impl From<FilterError> for HaystackError {
+    fn from(err: FilterError) -> HaystackError {
+        HaystackError::Filter(err)
+    }
+}
+
 impl From<io::Error> for HaystackError {
     fn from(err: io::Error) -> HaystackError {
         HaystackError::Io(err)
@@ -259,8 +331,8 @@ impl From<std::str::ParseBoolError> for HaystackError {
 //     }
 // }
 
-// impl From<serde_json::Error> for HaystackError {
-//     fn from(err: serde_json::Error) -> HaystackError {
-//         HaystackError::SerdeError(err)
-//     }
-// }
\ No newline at end of file
+impl From<serde_json::Error> for HaystackError {
+    fn from(err: serde_json::Error) -> HaystackError {
+        HaystackError::SerdeError(err)
+    }
+}
\ No newline at end of file
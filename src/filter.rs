@@ -1,8 +1,9 @@
 
-use crate::filter_shunting_yard::to_rpn;
+use crate::filter_shunting_yard::to_rpn_spanned;
 use std;
 use std::fmt;
-use filter_tokenizer::{tokenize, FilterToken, Operation};
+use std::cmp::Ordering;
+use filter_tokenizer::{tokenize_spanned, FilterToken, Operation};
 use chrono::{DateTime, Utc};
 
 use std::collections::{HashSet, HashMap};
@@ -11,6 +12,8 @@ use crate::*;
 use crate::error::*;
 use crate::token::Token;
 use crate::token::Tag;
+use crate::defs::{Namespace, conjunct_parts};
+use crate::units;
 
 use array_tool::vec::Intersect;
 use array_tool::uniques;
@@ -189,7 +192,210 @@ fn traverse_up_routes_removing_paths(original_routes: &Vec<Vec<(Token, Option<To
     routes
 }
 
+/// Ids of the entities that implement the def `name`.
+/// Without a namespace only the parts of a conjunct are checked, ie `elec-meter` needs `elec` and `meter`.
+fn ids_fitting_def(entities: &RefTags, name: &str, namespace: Option<&Namespace>) -> Vec<Token> {
+    entities.iter().filter(|&e| match namespace {
+        Some(ns) => ns.fits(&e.1, name),
+        None => conjunct_parts(name).iter().all(|p| e.1.contains(&Tag::new_marker(p))),
+    }).map(|e| e.0.clone()).collect()
+}
+
+/// The id of a ref ignoring its display string and any leading `@`, so `@x` and `x` are the same ref.
+pub fn ref_id(token: &Token) -> Option<&str> {
+    match token {
+        Token::Ref(id, _) => Some(id.strip_prefix('@').unwrap_or(id)),
+        _ => None,
+    }
+}
+
+/// The ref ids an entity's ref tags point at, with the name of each tag.
+fn ref_targets(tags: &[Tag]) -> Vec<(String, String)> {
+    tags.iter().filter_map(|tag| {
+        let target = tag.get_value::<Token>()?;
+        ref_id(&target).map(|id| (id.to_string(), tag.get_id()))
+    }).collect()
+}
+
+/// Index of which entities reference each entity, so refs can be followed backwards.
+///
+/// A point's `equipRef` points at its equip. The index answers the inverse question of which
+/// points reference a given equip, without scanning every entity. Keep it alongside the entities
+/// and update it with `insert` and `remove` as they change.
+#[derive(Debug, Clone, Default)]
+pub struct ReverseRefIndex {
+    // target ref id -> (referencing entity, ref tag name)
+    referrers: HashMap<String, Vec<(Token, String)>>,
+    // referencing ref id -> (target ref id, ref tag name)
+    targets: HashMap<String, Vec<(String, String)>>,
+}
+
+impl ReverseRefIndex {
+    pub fn new(entities: &RefTags) -> Self {
+        let mut index = ReverseRefIndex::default();
+
+        for entity in entities.iter() {
+            index.insert(entity);
+        }
+
+        index
+    }
+
+    /// Indexes the refs of an entity, replacing those it had before.
+    pub fn insert(&mut self, entity: &RefTag) {
+        let id = match ref_id(&entity.0) {
+            Some(id) => id.to_string(),
+            None => return,
+        };
+
+        self.remove(&entity.0);
+
+        let targets = ref_targets(&entity.1);
+
+        for (target, tag) in targets.iter() {
+            self.referrers.entry(target.clone()).or_default().push((entity.0.clone(), tag.clone()));
+        }
+
+        self.targets.insert(id, targets);
+    }
+
+    /// Forgets the refs of the entity `id`.
+    pub fn remove(&mut self, id: &Token) {
+        let id = match ref_id(id) {
+            Some(id) => id,
+            None => return,
+        };
+
+        for (target, _) in self.targets.remove(id).unwrap_or_default() {
+            if let Some(referrers) = self.referrers.get_mut(&target) {
+                referrers.retain(|(r, _)| ref_id(r) != Some(id));
+
+                if referrers.is_empty() {
+                    self.referrers.remove(&target);
+                }
+            }
+        }
+    }
+
+    /// Entities with any ref tag pointing at `target`.
+    pub fn referrers(&self, target: &Token) -> Vec<Token> {
+        self.entries(target).map(|(id, _)| id.clone()).unique().collect()
+    }
+
+    /// Entities whose `tag` ref points at `target`, ie the points of an equip via `equipRef`.
+    pub fn referrers_via(&self, target: &Token, tag: &str) -> Vec<Token> {
+        self.entries(target).filter(|(_, t)| t == tag).map(|(id, _)| id.clone()).unique().collect()
+    }
+
+    /// The ref ids that `referrers` point at through their `tag` refs, ie the equips of some points
+    /// via `equipRef`.
+    pub fn referenced_via(&self, referrers: &[Token], tag: &str) -> HashSet<String> {
+        referrers.iter()
+            .filter_map(|r| ref_id(r).and_then(|id| self.targets.get(id)))
+            .flatten()
+            .filter(|(_, t)| t == tag)
+            .map(|(target, _)| target.clone())
+            .collect()
+    }
+
+    fn entries<'a>(&'a self, target: &Token) -> impl Iterator<Item = &'a (Token, String)> {
+        ref_id(target).and_then(|id| self.referrers.get(id)).into_iter().flatten()
+    }
+}
+
+/// Returns the entities that are referenced through `ref_tag` by an entity matching `expr`.
+///
+/// This follows refs in reverse, ie equips that have a point with an alarm:
+///
+/// ```rust
+/// use libproject_haystack_rs::prelude::*;
+///
+/// let values: RefTags = vec![
+///     (Token::Ref("equip1".to_string(), None), vec![Tag::new_marker("equip")]),
+///     (Token::Ref("point1".to_string(), None), vec![Tag::new_marker("alarm"), Tag::new_ref("equipRef", "equip1")]),
+/// ];
+///
+/// let equips = filter_eval_str_referenced_by("alarm", "equipRef", &values).unwrap();
+/// assert_eq!(equips[0].0, Token::Ref("equip1".to_string(), None));
+/// ```
+///
+/// Servers answer this from the index a `HaystackDatabase` keeps, through `read_referenced_by`.
+pub fn filter_eval_str_referenced_by(expr: &str, ref_tag: &str, values: &RefTags) -> Result<RefTags, FilterError>
+{
+    filter_eval_str_referenced_by_with_index(expr, ref_tag, values, &ReverseRefIndex::new(values))
+}
+
+/// As `filter_eval_str_referenced_by`, following the refs through an index kept of `values`.
+pub fn filter_eval_str_referenced_by_with_index(expr: &str, ref_tag: &str, values: &RefTags, index: &ReverseRefIndex) -> Result<RefTags, FilterError>
+{
+    let matched: Vec<Token> = filter_eval_str(expr, values)?.into_iter().map(|(id, _)| id).collect();
+    let referenced = index.referenced_via(&matched, ref_tag);
+
+    Ok(values.iter()
+        .filter(|(id, _)| ref_id(id).is_some_and(|id| referenced.contains(id)))
+        .cloned()
+        .collect())
+}
+
+/// Compares a tag value against the value in a filter comparison.
+///
+/// Numbers with different units are converted to the unit of the tag value first, so
+/// `curVal > 70°F` matches a `curVal` of `22°C`. A number without a unit compares with any unit.
+fn compare_tokens(value: &Token, op: &Operation, other: &Token) -> Result<bool, FilterError> {
+    let ordering: Option<Ordering> = match (value, other) {
+        (Token::Number(a, a_unit), Token::Number(b, b_unit)) => {
+            let b = if a_unit.is_empty() || b_unit.is_empty() {
+                b.number
+            }
+            else {
+                units::convert(b.number, b_unit, a_unit)
+                    .ok_or_else(|| FilterError::IncompatibleUnits(a_unit.to_string(), b_unit.to_string()))?
+            };
+
+            // Allow for rounding in the unit conversion
+            if (a.number - b).abs() <= 1e-9 * a.number.abs().max(b.abs()).max(1.0) {
+                Some(Ordering::Equal)
+            }
+            else {
+                a.number.partial_cmp(&b)
+            }
+        },
+        // A ref matches with or without its leading @
+        (Token::Ref(..), Token::Ref(..)) => Some(ref_id(value).cmp(&ref_id(other))),
+        _ => Some(value.cmp(other)),
+    };
+
+    let ordering = match ordering {
+        Some(o) => o,
+        None => return Ok(false),   // NaN
+    };
+
+    match op {
+        Operation::Equals => Ok(ordering == Ordering::Equal),
+        Operation::NotEquals => Ok(ordering != Ordering::Equal),
+        Operation::MoreThan => Ok(ordering == Ordering::Greater),
+        Operation::MoreThanEquals => Ok(ordering != Ordering::Less),
+        Operation::LessThan => Ok(ordering == Ordering::Less),
+        Operation::LessThanEquals => Ok(ordering != Ordering::Greater),
+        _ => Err(FilterError::EvalError("Unexpected comparison operation".to_string())),
+    }
+}
+
 pub fn filter_eval_str(expr: &str, values: &RefTags) -> Result<RefTags, FilterError> 
+{
+    filter_eval(expr, values, None)
+}
+
+/// Evaluates a filter consulting a defs namespace.
+///
+/// A single tag term such as `ahu` matches entities whose tags inherit from it, ie an entity
+/// tagged `rtu`. `^symbol` terms match entities that implement the def.
+pub fn filter_eval_str_with_defs(expr: &str, values: &RefTags, namespace: &Namespace) -> Result<RefTags, FilterError> 
+{
+    filter_eval(expr, values, Some(namespace))
+}
+
+fn filter_eval(expr: &str, values: &RefTags, namespace: Option<&Namespace>) -> Result<RefTags, FilterError> 
 {
     let mut stack : Vec<StackValue> = Vec::with_capacity(16);
 
@@ -198,8 +404,8 @@ pub fn filter_eval_str(expr: &str, values: &RefTags) -> Result<RefTags, FilterEr
     // let mut haystack_tag_name_store: HashMap<String, Vec<Token>> = HashMap::new();
     // let mut haystack_ref_name_store: HashMap<Token, HaystackTags> = HashMap::new();
 
-    let tokens = tokenize(expr)?;
-    let rpn = to_rpn(&tokens)?;
+    let tokens = tokenize_spanned(expr)?;
+    let rpn = to_rpn_spanned(&tokens)?;
 
     'rpn_loop: for token in &rpn {
 
@@ -208,6 +414,15 @@ pub fn filter_eval_str(expr: &str, values: &RefTags) -> Result<RefTags, FilterEr
             FilterToken::Val(ref n) => {
                 stack.push(StackValue::Token(n.clone()));
             },
+            FilterToken::Symbol(ref name) => {
+                stack.push(StackValue::Refs(ids_fitting_def(values, name, namespace)));
+            },
+            FilterToken::Path(ref tags) if tags.len() == 1 && namespace.is_some() => {
+                match tags[0] {
+                    Token::Id(ref name) => stack.push(StackValue::Refs(ids_fitting_def(values, name, namespace))),
+                    _ => return Err(FilterError::EvalError("Unexpected type".to_string())),
+                }
+            },
             FilterToken::Path(ref tags) => {   // Name here is a tag. We need to get all the refs with that tag
 
                 // Ok path may be one tag name like vec![elec] 
@@ -253,51 +468,8 @@ pub fn filter_eval_str(expr: &str, values: &RefTags) -> Result<RefTags, FilterEr
                                 _ => None
                             };
 
-                            match op {
-                                Operation::Equals => {
-    
-                                    // println!("Equals: {:?}  {:?}", value, token_val);
-
-                                    if value == token_val.unwrap() {
-                                        new_leaves.push(leaf.clone());
-                                    }
-                                },
-                                Operation::MoreThan => {
-    
-                                    // println!("Equals: {:?}  {:?}", value, token_val);
-
-                                    if value > token_val.unwrap() {
-                                        new_leaves.push(leaf.clone());
-                                    }
-                                },
-                                Operation::MoreThanEquals => {
-    
-                                    // println!("Equals: {:?}  {:?}", value, token_val);
-
-                                    if value >= token_val.unwrap() {
-                                        new_leaves.push(leaf.clone());
-                                    }
-                                },
-                                Operation::LessThan => {
-    
-                                    // println!("Equals: {:?}  {:?}", value, token_val);
-
-                                    if value < token_val.unwrap() {
-                                        new_leaves.push(leaf.clone());
-                                    }
-                                },
-                                Operation::LessThanEquals => {
-    
-                                    // println!("Equals: {:?}  {:?}", value, token_val);
-
-                                    if value <= token_val.unwrap() {
-                                        new_leaves.push(leaf.clone());
-                                    }
-                                },
-                                _ => {
-                                    return Err(FilterError::EvalError("Unexpected comparison operation".to_string()));
-                                }
-
+                            if compare_tokens(&value, op, &token_val.unwrap())? {
+                                new_leaves.push(leaf.clone());
                             }
                         }
 
@@ -432,6 +604,8 @@ pub fn filter_eval_str(expr: &str, values: &RefTags) -> Result<RefTags, FilterEr
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filter_shunting_yard::to_rpn;
+    use crate::filter_tokenizer::tokenize;
 
     macro_rules! refs {
         ( $( $x:expr ),* ) => {
@@ -598,4 +772,85 @@ mod tests {
         assert_eq!(filter_tokens!(filter_eval_str("carnego_number_of_bedrooms > 1.0", &values)), refs!("11"));
 
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_eval_with_defs() {
+
+        let values: RefTags = vec![
+            (Token::Ref("1".to_string(), None), vec![Tag::new_marker("ahu"), Tag::new_marker("equip")]),
+            (Token::Ref("2".to_string(), None), vec![Tag::new_marker("rtu"), Tag::new_marker("equip")]),
+            (Token::Ref("3".to_string(), None), vec![Tag::new_marker("elec"), Tag::new_marker("meter"), Tag::new_marker("equip")]),
+            (Token::Ref("4".to_string(), None), vec![Tag::new_marker("site")]),
+        ];
+
+        let ns = Namespace::load("test_files/defs.zinc").unwrap();
+
+        assert_eq!(filter_tokens!(filter_eval_str("ahu", &values)), refs!("1"));
+        assert_eq!(filter_tokens!(filter_eval_str_with_defs("ahu", &values, &ns)), refs!("1", "2"));
+        assert_eq!(filter_tokens!(filter_eval_str_with_defs("ahu and not rtu", &values, &ns)), refs!("1"));
+
+        assert_eq!(filter_tokens!(filter_eval_str("^elec-meter", &values)), refs!("3"));
+        assert_eq!(filter_tokens!(filter_eval_str_with_defs("^meter or site", &values, &ns)), refs!("3", "4"));
+        assert_eq!(filter_tokens!(filter_eval_str_with_defs("^airHandlingEquip", &values, &ns)), refs!("1", "2"));
+    }
+
+    #[test]
+    fn test_eval_units() {
+
+        let values: RefTags = vec![
+            (Token::Ref("1".to_string(), None), vec![Tag::new_marker("point"), Tag::new_number("curVal", 22.0, "°C")]),
+            (Token::Ref("2".to_string(), None), vec![Tag::new_marker("point"), Tag::new_number("curVal", 68.0, "°F")]),
+        ];
+
+        assert_eq!(filter_tokens!(filter_eval_str("point and curVal > 70°F", &values)), refs!("1"));
+        assert_eq!(filter_tokens!(filter_eval_str("point and curVal <= 20°C", &values)), refs!("2"));
+        assert_eq!(filter_tokens!(filter_eval_str("curVal > 1", &values)), refs!("1", "2"));
+
+        let power: RefTags = vec![
+            (Token::Ref("3".to_string(), None), vec![Tag::new_marker("point"), Tag::new_number("curVal", 1.5, "kW")]),
+        ];
+
+        assert_eq!(filter_tokens!(filter_eval_str("curVal == 1500W", &power)), refs!("3"));
+        assert_eq!(filter_eval_str("curVal > 70°F", &power),
+            Err(FilterError::IncompatibleUnits("kW".to_string(), "°F".to_string())));
+    }
+
+    #[test]
+    fn test_reverse_refs() {
+
+        let values: RefTags = vec![
+            (Token::Ref("site".to_string(), None), vec![Tag::new_marker("site")]),
+            (Token::Ref("ahu".to_string(), None), vec![Tag::new_marker("equip"), Tag::new_ref("siteRef", "site")]),
+            (Token::Ref("boiler".to_string(), None), vec![Tag::new_marker("equip"), Tag::new_ref("siteRef", "site")]),
+            (Token::Ref("p1".to_string(), None), vec![Tag::new_marker("point"), Tag::new_marker("alarm"),
+                                                      Tag::new_ref("equipRef", "ahu"), Tag::new_ref("siteRef", "site")]),
+            (Token::Ref("p2".to_string(), None), vec![Tag::new_marker("point"), Tag::new_ref("equipRef", "boiler")]),
+        ];
+
+        let mut index = ReverseRefIndex::new(&values);
+        assert_eq!(index.referrers(&token_ref!("site")), refs!("ahu", "boiler", "p1"));
+        assert_eq!(index.referrers_via(&token_ref!("ahu"), "equipRef"), refs!("p1"));
+        assert_eq!(index.referrers_via(&token_ref!("ahu"), "siteRef"), refs!());
+        assert_eq!(index.referrers(&Token::Ref("ahu".to_string(), Some("AHU-1".to_string()))), refs!("p1"));
+        assert_eq!(index.referrers(&token_ref!("@ahu")), refs!("p1"));
+        assert_eq!(index.referenced_via(&refs!("p1", "p2"), "equipRef"), ["ahu".to_string(), "boiler".to_string()].iter().cloned().collect());
+
+        // Kept up to date as entities change
+        index.insert(&(token_ref!("p2"), vec![Tag::new_marker("point"), Tag::new_ref("equipRef", "@ahu")]));
+        assert_eq!(index.referrers_via(&token_ref!("ahu"), "equipRef"), refs!("p1", "p2"));
+        assert_eq!(index.referrers_via(&token_ref!("boiler"), "equipRef"), refs!());
+        index.remove(&token_ref!("@p1"));
+        assert_eq!(index.referrers(&token_ref!("ahu")), refs!("p2"));
+        assert_eq!(index.referrers(&token_ref!("site")), refs!("ahu", "boiler"));
+
+        assert_eq!(filter_tokens!(filter_eval_str_referenced_by("point and alarm", "equipRef", &values)), refs!("ahu"));
+        assert_eq!(filter_tokens!(filter_eval_str_referenced_by("point", "equipRef", &values)), refs!("ahu", "boiler"));
+        assert_eq!(filter_tokens!(filter_eval_str_referenced_by("equip", "siteRef", &values)), refs!("site"));
+
+        // The refs come from the index, which has p2 on the ahu and p1 gone
+        assert_eq!(filter_tokens!(filter_eval_str_referenced_by_with_index("point", "equipRef", &values, &index)), refs!("ahu"));
+
+        // Refs compare with or without the @
+        assert_eq!(filter_tokens!(filter_eval_str("equipRef == @ahu", &values)), refs!("p1"));
+    }
+}
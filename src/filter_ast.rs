@@ -0,0 +1,245 @@
+//! Abstract syntax tree for haystack filters.
+//!
+//! The tokenizer and shunting-yard stages produce a flat RPN sequence that is convenient to
+//! evaluate but cannot be printed back out. `FilterNode` is built from that RPN and can be
+//! displayed as canonical filter syntax, and normalised so that equivalent filters compare equal.
+//!
+//! ```rust
+//! use libproject_haystack_rs::filter_ast::FilterNode;
+//!
+//! let a = FilterNode::parse("heat and (elec and site)").unwrap().normalize();
+//! let b = FilterNode::parse("site and elec and heat").unwrap().normalize();
+//! assert_eq!(a, b);
+//! assert_eq!(a.to_string(), "elec and heat and site");
+//! ```
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::*;
+use crate::filter_shunting_yard::to_rpn_spanned;
+use crate::filter_tokenizer::{tokenize_spanned, FilterToken, Operation};
+use crate::hval::HVal;
+use crate::token::Token;
+
+/// A path of tag names, ie `siteRef->geoCity` is `vec!["siteRef", "geoCity"]`.
+pub type FilterPath = Vec<String>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FilterNode {
+    And(Vec<FilterNode>),
+    Or(Vec<FilterNode>),
+    Not(Box<FilterNode>),
+    /// Entity has the tag at the end of the path.
+    Has(FilterPath),
+    /// Entity does not have the tag at the end of the path.
+    Missing(FilterPath),
+    Cmp(FilterPath, Operation, Token),
+    /// Entity implements the def, ie ^elec-meter
+    Symbol(String),
+}
+
+fn path_from_tokens(tokens: &[Token]) -> Result<FilterPath, FilterError> {
+    tokens.iter().map(|t| match t {
+        Token::Id(name) => Ok(name.to_string()),
+        _ => Err(FilterError::EvalError(format!("Unexpected path element: {:?}", t))),
+    }).collect()
+}
+
+fn fmt_path(path: &[String]) -> String {
+    path.join("->")
+}
+
+/// Formats a value the way it is written in a filter. This is zinc except for booleans.
+fn fmt_val(val: &Token) -> String {
+    match val {
+        Token::Bool(b) => b.to_string(),
+        _ => val.to_zinc(),
+    }
+}
+
+impl FilterNode {
+
+    /// Parses a filter string into an un-normalised tree.
+    pub fn parse(expr: &str) -> Result<FilterNode, FilterError> {
+        let tokens = tokenize_spanned(expr)?;
+        let rpn = to_rpn_spanned(&tokens)?;
+        FilterNode::from_rpn(&rpn)
+    }
+
+    /// Builds a tree from the output of `to_rpn`.
+    pub fn from_rpn(rpn: &[FilterToken]) -> Result<FilterNode, FilterError> {
+        let mut stack: Vec<FilterNode> = Vec::with_capacity(rpn.len());
+
+        for token in rpn {
+            match token {
+                FilterToken::Path(tags) => stack.push(FilterNode::Has(path_from_tokens(tags)?)),
+                FilterToken::Symbol(name) => stack.push(FilterNode::Symbol(name.to_string())),
+                FilterToken::Compare(path, op, val) => {
+                    let path = match **path {
+                        FilterToken::Path(ref tags) => path_from_tokens(tags)?,
+                        _ => return Err(FilterError::EvalError("Unexpected type".to_string())),
+                    };
+
+                    let val = match **val {
+                        FilterToken::Val(ref v) => v.clone(),
+                        _ => return Err(FilterError::EvalError("Unexpected type".to_string())),
+                    };
+
+                    stack.push(FilterNode::Cmp(path, *op, val));
+                },
+                FilterToken::Binary(op) => {
+                    let (right, left) = match (stack.pop(), stack.pop()) {
+                        (Some(r), Some(l)) => (r, l),
+                        _ => return Err(FilterError::EvalError(format!("Missing operands for {:?}", op))),
+                    };
+
+                    match op {
+                        Operation::And => stack.push(FilterNode::And(vec![left, right])),
+                        Operation::Or => stack.push(FilterNode::Or(vec![left, right])),
+                        _ => return Err(FilterError::EvalError(format!("Unimplemented binary operation: {:?}", op))),
+                    }
+                },
+                FilterToken::Unary(Operation::Not) => {
+                    let x = stack.pop().ok_or_else(|| FilterError::EvalError("Missing operand for not".to_string()))?;
+
+                    match x {
+                        FilterNode::Has(path) => stack.push(FilterNode::Missing(path)),
+                        _ => stack.push(FilterNode::Not(Box::new(x))),
+                    }
+                },
+                _ => return Err(FilterError::EvalError(format!("Unrecognized token: {:?}", token))),
+            }
+        }
+
+        let node = stack.pop().ok_or_else(|| FilterError::EvalError("Empty filter".to_string()))?;
+
+        if !stack.is_empty() {
+            return Err(FilterError::EvalError(format!("There are still {} items on the stack.", stack.len())));
+        }
+
+        Ok(node)
+    }
+
+    /// Returns an equivalent tree in canonical form.
+    ///
+    /// Nested `and`/`or` terms are flattened, double negations and negated tag checks are
+    /// simplified, and duplicate terms removed. The terms of each `and`/`or` are sorted by their
+    /// filter syntax so the result does not depend on how the filter was written.
+    pub fn normalize(&self) -> FilterNode {
+        match self {
+            FilterNode::And(children) => FilterNode::normalize_group(children, true),
+            FilterNode::Or(children) => FilterNode::normalize_group(children, false),
+            FilterNode::Not(x) => match x.normalize() {
+                FilterNode::Has(path) => FilterNode::Missing(path),
+                FilterNode::Missing(path) => FilterNode::Has(path),
+                FilterNode::Not(inner) => *inner,
+                other => FilterNode::Not(Box::new(other)),
+            },
+            _ => self.clone(),
+        }
+    }
+
+    fn normalize_group(children: &[FilterNode], is_and: bool) -> FilterNode {
+        let mut terms: Vec<FilterNode> = vec![];
+
+        for child in children.iter().map(|c| c.normalize()) {
+            match child {
+                FilterNode::And(nested) if is_and => terms.extend(nested),
+                FilterNode::Or(nested) if !is_and => terms.extend(nested),
+                _ => terms.push(child),
+            }
+        }
+
+        terms.sort_by_cached_key(|t| t.to_string());
+        terms.dedup();
+
+        if terms.len() == 1 {
+            return terms.remove(0);
+        }
+
+        if is_and { FilterNode::And(terms) } else { FilterNode::Or(terms) }
+    }
+}
+
+impl FromStr for FilterNode {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FilterNode::parse(s)
+    }
+}
+
+impl fmt::Display for FilterNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterNode::And(children) => {
+                let s = children.iter().map(|c| match c {
+                    FilterNode::Or(_) => format!("({})", c),
+                    _ => c.to_string(),
+                }).collect::<Vec<String>>().join(" and ");
+
+                write!(f, "{}", s)
+            },
+            FilterNode::Or(children) => {
+                let s = children.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(" or ");
+                write!(f, "{}", s)
+            },
+            FilterNode::Not(x) => match **x {
+                FilterNode::And(_) | FilterNode::Or(_) => write!(f, "not ({})", x),
+                _ => write!(f, "not {}", x),
+            },
+            FilterNode::Has(path) => write!(f, "{}", fmt_path(path)),
+            FilterNode::Missing(path) => write!(f, "not {}", fmt_path(path)),
+            FilterNode::Cmp(path, op, val) => write!(f, "{} {} {}", fmt_path(path), op, fmt_val(val)),
+            FilterNode::Symbol(name) => write!(f, "^{}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(expr: &str) -> String {
+        FilterNode::parse(expr).unwrap().to_string()
+    }
+
+    fn canonical(expr: &str) -> FilterNode {
+        FilterNode::parse(expr).unwrap().normalize()
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(round_trip("elec"), "elec");
+        assert_eq!(round_trip("not elec"), "not elec");
+        assert_eq!(round_trip("siteRef->geoCity"), "siteRef->geoCity");
+        assert_eq!(round_trip("elec and heat"), "elec and heat");
+        assert_eq!(round_trip("elec and (heat or water)"), "elec and (heat or water)");
+        assert_eq!(round_trip("elec or heat and water"), "elec or heat and water");
+        assert_eq!(round_trip("geoCity == \"Chicago\""), "geoCity == \"Chicago\"");
+        assert_eq!(round_trip("siteRef == @site1"), "siteRef == @site1");
+        assert_eq!(round_trip("carnego_number_of_bedrooms > 5"), "carnego_number_of_bedrooms > 5");
+        assert_eq!(round_trip("occupied == true"), "occupied == true");
+        assert_eq!(round_trip("not (elec or heat)"), "not (elec or heat)");
+        assert_eq!(round_trip("^elec-meter and siteRef"), "^elec-meter and siteRef");
+    }
+
+    #[test]
+    fn reparse_test() {
+        for expr in &["elec and (heat or not water)", "siteRef->geoCity == \"Chicago\" or heat", "not (elec and heat)"] {
+            let node = FilterNode::parse(expr).unwrap();
+            assert_eq!(FilterNode::parse(&node.to_string()).unwrap(), node);
+        }
+    }
+
+    #[test]
+    fn normalize_test() {
+        assert_eq!(canonical("heat and (elec and site)"), canonical("site and elec and heat"));
+        assert_eq!(canonical("heat or (elec or heat)"), canonical("elec or heat"));
+        assert_eq!(canonical("heat and heat"), FilterNode::Has(vec!["heat".to_string()]));
+        assert_eq!(canonical("not (not elec)"), canonical("elec"));
+        assert_ne!(canonical("elec and heat"), canonical("elec or heat"));
+
+        assert_eq!(canonical("water and (heat or elec)").to_string(), "(elec or heat) and water");
+    }
+}
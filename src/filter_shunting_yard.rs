@@ -66,6 +66,7 @@ pub fn to_rpn(input: &[FilterToken]) -> Result<Vec<FilterToken>, RPNError> {
             FilterToken::Val(_) => output.push(token),
             FilterToken::Path(_) => output.push(token),
             FilterToken::Compare(_, _, _) => output.push(token),
+            FilterToken::Symbol(_) => output.push(token),
             FilterToken::Unary(_) => stack.push((index, token)),
             FilterToken::Binary(_) => {
                 let pa1 = prec_assoc(&token);
@@ -119,6 +120,7 @@ pub fn to_rpn(input: &[FilterToken]) -> Result<Vec<FilterToken>, RPNError> {
             FilterToken::Val(_) => n_operands += 1,
             FilterToken::Path(_) => n_operands += 1,
             FilterToken::Compare(_, _, _) => n_operands += 1,
+            FilterToken::Symbol(_) => n_operands += 1,
             FilterToken::Unary(_) => (),
             FilterToken::Binary(_) => n_operands -= 1,
             _ => panic!("Nothing else should be here"),
@@ -136,6 +138,30 @@ pub fn to_rpn(input: &[FilterToken]) -> Result<Vec<FilterToken>, RPNError> {
     Ok(output)
 }
 
+/// Like `to_rpn` but takes the output of `tokenize_spanned`, so errors point into the filter string.
+pub fn to_rpn_spanned(input: &[(FilterToken, Span)]) -> Result<Vec<FilterToken>, FilterError> {
+
+    let tokens: Vec<FilterToken> = input.iter().map(|(t, _)| t.clone()).collect();
+
+    // Span of everything, for errors that are not about one token
+    let all = match (input.first(), input.last()) {
+        (Some((_, first)), Some((_, last))) => Span::new(first.start, last.end),
+        _ => Span::new(0, 0),
+    };
+
+    to_rpn(&tokens).map_err(|e| {
+        let e = match e {
+            RPNError::MismatchedLParen(i) => FilterTokenParseError::Expected("`)` to close `(`".to_string(), input[i].1),
+            RPNError::MismatchedRParen(i) => FilterTokenParseError::Unexpected("`)`".to_string(), input[i].1),
+            RPNError::UnexpectedComma(i) => FilterTokenParseError::Unexpected("`,`".to_string(), input[i].1),
+            RPNError::NotEnoughOperands(_) => FilterTokenParseError::Expected("another operand".to_string(), all),
+            RPNError::TooManyOperands => FilterTokenParseError::Expected("`and` or `or` between terms".to_string(), all),
+        };
+
+        FilterError::ParseError(e)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -11,10 +11,12 @@ use nom::{
 
 use chrono::{Date, DateTime, Datelike, FixedOffset, NaiveDateTime, TimeZone, Utc};
 
+use std::fmt;
+
 use crate::hval::HVal;
 use crate::token::*;
-use crate::error::FilterTokenParseError;
-use crate::zinc_tokenizer::{number_with_unit, zinc_ref, quoted_string, time_with_subseconds, uri, date, zinc_id};
+use crate::error::{FilterTokenParseError, Span};
+use crate::zinc_tokenizer::{number_with_unit, zinc_ref, quoted_string, time_with_subseconds, uri, date, zinc_id, symbol};
 
 fn filter_bool<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
     map(alt((tag("true"), tag("false"))), |o: &str| {
@@ -142,8 +144,8 @@ fn path2<'a>(i: &'a str) -> IResult<&'a str, FilterToken, (&'a str, ErrorKind)>
 // }
 
 fn cmp_op<'a>(i: &'a str) -> IResult<&'a str, Operation, (&'a str, ErrorKind)> {
-    // one_of matches one of the characters we give it
-    let (i, t) = alt((tag("=="), tag("!="), tag("<"), tag("<="), tag(">"), tag(">=")))(i)?;
+    // <= and >= must be tried before < and >
+    let (i, t) = alt((tag("=="), tag("!="), tag("<="), tag("<"), tag(">="), tag(">")))(i)?;
   
     Ok((
       i,
@@ -221,9 +223,20 @@ fn not<'a>(i: &'a str) -> IResult<&'a str, FilterToken, (&'a str, ErrorKind)> {
     map(tag("not"), |o: &str| { FilterToken::Unary(Operation::Not)})(i)
 }
 
+// ^elec-meter
+// Matches entities that implement the def
+fn filter_symbol<'a>(i: &'a str) -> IResult<&'a str, FilterToken, (&'a str, ErrorKind)> {
+    map(symbol, |t: Token| {
+        match t {
+            Token::Symbol(name) => FilterToken::Symbol(name),
+            _ => unreachable!(),
+        }
+    })(i)
+}
+
 fn term<'a>(i: &'a str) -> IResult<&'a str, FilterToken, (&'a str, ErrorKind)> {
 
-    alt((cmp, not, path))(i)
+    alt((cmp, not, filter_symbol, path))(i)
 }
 
 fn term2<'a>(i: &'a str) -> IResult<&'a str, FilterToken, (&'a str, ErrorKind)> {
@@ -257,12 +270,14 @@ pub enum FilterToken {
     //Name(String),
     Path(Vec<Token>),   // Vector of id types
     Val(Token),
+    /// Def symbol term, ie ^elec-meter
+    Symbol(String),
 }
 
 
 
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Operation {
     Or,
     And,
@@ -276,6 +291,23 @@ pub enum Operation {
     MoreThanEquals,
 }
 
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Operation::Or => "or",
+            Operation::And => "and",
+            Operation::Not => "not",
+            Operation::Equals => "==",
+            Operation::NotEquals => "!=",
+            Operation::LessThan => "<",
+            Operation::LessThanEquals => "<=",
+            Operation::MoreThan => ">",
+            Operation::MoreThanEquals => ">=",
+        };
+
+        write!(f, "{}", s)
+    }
+}
 
 
 /// Continuing the trend of starting from the simplest piece and building up,
@@ -368,6 +400,56 @@ enum ParenState {
     Subexpr,
 }
 
+/// Number of chars of `input` before `rest`, where `rest` is a suffix of `input`.
+fn char_offset(input: &str, rest: &str) -> usize {
+    input[..input.len() - rest.len()].chars().count()
+}
+
+/// Span of the next word in `rest`, used to point at the text that failed to parse.
+fn next_word_span(input: &str, rest: &str) -> Span {
+    let rest = rest.trim_start();
+    let start = char_offset(input, rest);
+    let len = rest.chars().take_while(|c| !c.is_whitespace()).count();
+    Span::new(start, start + len)
+}
+
+/// How a token is quoted in error messages, ie `==` or `siteRef->dis`.
+fn describe(token: &FilterToken) -> String {
+    match token {
+        FilterToken::Binary(op) | FilterToken::Unary(op) => format!("`{}`", op),
+        FilterToken::LParen => "`(`".to_string(),
+        FilterToken::RParen => "`)`".to_string(),
+        FilterToken::Path(tags) => format!("`{}`", tags.iter().map(|t| t.to_string()).collect::<Vec<String>>().join("->")),
+        FilterToken::Symbol(name) => format!("`^{}`", name),
+        FilterToken::Val(v) => format!("`{}`", v.to_zinc()),
+        FilterToken::Compare(_, _, val) => describe(val),
+    }
+}
+
+/// Builds the error for input that could not be tokenized at `rest`.
+fn expected_error(input: &str, rest: &str, state: TokenizerState, in_parens: bool, prev: Option<&FilterToken>) -> FilterTokenParseError {
+
+    // A path followed by a comparison operator but no value, ie curVal ==
+    if let Some(FilterToken::Path(_)) = prev {
+        if let Ok((after_op, op)) = delimited(multispace0, cmp_op, multispace0)(rest) {
+            return FilterTokenParseError::Expected(format!("value after `{}`", op), next_word_span(input, after_op));
+        }
+    }
+
+    let expected = match state {
+        TokenizerState::LExpr => "tag name, `not` or `(`",
+        TokenizerState::AfterRExpr if in_parens => "`and`, `or` or `)`",
+        TokenizerState::AfterRExpr => "`and` or `or`",
+    };
+
+    let expected = match prev {
+        Some(t) => format!("{} after {}", expected, describe(t)),
+        None => expected.to_string(),
+    };
+
+    FilterTokenParseError::Expected(expected, next_word_span(input, rest))
+}
+
 /// Tokenize a given mathematical expression.
 ///
 /// The parser should return `Ok` only if the expression is well-formed.
@@ -376,37 +458,44 @@ enum ParenState {
 ///
 /// Returns `Err` if the expression is not well-formed.
 pub fn tokenize(input: &str) -> Result<Vec<FilterToken>, FilterTokenParseError> {
+    Ok(tokenize_spanned(input)?.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Like `tokenize` but also returns the position of each token in `input`.
+///
+/// Errors carry the span of the offending text and a hint of what was expected there.
+pub fn tokenize_spanned(input: &str) -> Result<Vec<(FilterToken, Span)>, FilterTokenParseError> {
     let mut state: TokenizerState = TokenizerState::LExpr;
-    // number of function arguments left
-    let mut paren_stack = vec![];
+    // spans of the open parens
+    let mut paren_stack: Vec<(ParenState, Span)> = vec![];
 
-    let mut res = vec![];
+    let mut res: Vec<(FilterToken, Span)> = vec![];
 
     let mut s = input;
 
-    while !s.is_empty() {
-
-        // println!("s: {:?},   state: {:?}", s, state);
+    while !s.trim_start().is_empty() {
 
         let r = match (state, paren_stack.last()) {
             (TokenizerState::AfterRExpr, None) => after_rexpr_no_paren(s),
-            (TokenizerState::AfterRExpr, Some(&ParenState::Subexpr)) => after_rexpr(s),
+            (TokenizerState::AfterRExpr, Some(&(ParenState::Subexpr, _))) => after_rexpr(s),
             (TokenizerState::LExpr, _) => lexpr(s),
         };
 
-        // println!("r: {:?}", r);
-
         match r {
             Ok((rest, t)) => {
 
+                let text = s[..s.len() - rest.len()].trim();
+                let start = char_offset(input, s.trim_start());
+                let span = Span::new(start, start + text.chars().count());
+
                 match t {
                     FilterToken::LParen => {
-                        paren_stack.push(ParenState::Subexpr);
+                        paren_stack.push((ParenState::Subexpr, span));
                     }
                     FilterToken::RParen => {
                         paren_stack.pop().expect("The paren_stack is empty!");
                     }
-                    FilterToken::Val(_) | FilterToken::Path(_) | FilterToken::Compare(_, _, _) => {
+                    FilterToken::Val(_) | FilterToken::Path(_) | FilterToken::Compare(_, _, _) | FilterToken::Symbol(_) => {
                         state = TokenizerState::AfterRExpr;
                     }
                     FilterToken::Binary(_) => {
@@ -414,48 +503,28 @@ pub fn tokenize(input: &str) -> Result<Vec<FilterToken>, FilterTokenParseError>
                     }
                     _ => {}
                 }
-                res.push(t);
+                res.push((t, span));
                 s = rest;
             }
-            Err(e) => {
-        
-                //match e {
-                //    Err::Error((value, _)) => {
-               //         return Err(FilterTokenParseError::UnexpectedStrToken(value.to_string()));
-                //    },
-                //    _ => (),
-              //  }
-
-                println!("Tokenize {:?}", e);
-
-                return Err(FilterTokenParseError::UnknownFilterTokenParseError);
+            Err(_) => {
+                return Err(expected_error(input, s, state, !paren_stack.is_empty(), res.last().map(|(t, _)| t)));
             }
-            // Error(Err::Position(_, p)) => {
-            //     let (i, _) = slice_to_offsets(input, p);
-            //     return Err(FilterTokenParseError::UnexpectedToken(i));
-            // }
-            // _ => {
-            //     panic!("Unexpected parse result when parsing `{}` at `{}`: {:?}", input, s, r);
-            // }
         }
-
     }
 
     match state {
         TokenizerState::LExpr => {
-            Err(FilterTokenParseError::MissingArgument)
+            Err(expected_error(input, s, state, !paren_stack.is_empty(), res.last().map(|(t, _)| t)))
         },
 
         _ => {
-            if !paren_stack.is_empty() {
-                return Err(FilterTokenParseError::MissingRParen(paren_stack.len() as i32));
+            if let Some((_, span)) = paren_stack.last() {
+                return Err(FilterTokenParseError::Expected("`)` to close `(`".to_string(), *span));
             }
 
-            return Ok(res);
+            Ok(res)
         }
     }
-
-
 }
 
 // New way to tokensise. Stop tokenising binary ops as one unit and returning FilterToken::Compare
@@ -636,6 +705,12 @@ mod tests {
             id_to_path!("heat"),
         ]));
 
+        assert_eq!(tokenize("^elec-meter and site"), Ok(vec![
+            Symbol("elec-meter".to_string()),
+            Binary(And),
+            id_to_path!("site"),
+        ]));
+
         assert_eq!(tokenize("elec->heat"), Ok(vec![
             FilterToken::Path(vec![id_to_token!("elec"), id_to_token!("heat")])
         ]));
@@ -803,4 +878,29 @@ mod tests {
         println!("{:?}", lexpr2("equip and siteRef->geoCity->dis == \"Chicago\""));
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn spanned_test() {
+
+        use super::FilterToken::*;
+
+        assert_eq!(tokenize_spanned("elec and  (heat)"), Ok(vec![
+            (id_to_path!("elec"), Span::new(0, 4)),
+            (Binary(Operation::And), Span::new(5, 8)),
+            (LParen, Span::new(10, 11)),
+            (id_to_path!("heat"), Span::new(11, 15)),
+            (RParen, Span::new(15, 16)),
+        ]));
+
+        let err = |s: &str| tokenize_spanned(s).unwrap_err().to_string();
+
+        assert_eq!(err("point and curVal == "), "Expected value after `==` at column 21.");
+        assert_eq!(err("point and curVal == and"), "Expected value after `==` at column 21.");
+        assert_eq!(err("elec and"), "Expected tag name, `not` or `(` after `and` at column 9.");
+        assert_eq!(err("elec heat"), "Expected `and` or `or` after `elec` at column 6.");
+        assert_eq!(err("(elec heat)"), "Expected `and`, `or` or `)` after `elec` at column 7.");
+        assert_eq!(err("elec and (heat"), "Expected `)` to close `(` at column 10.");
+
+        assert_eq!(tokenize_spanned("elec and").unwrap_err().span(), Some(Span::new(8, 8)));
+    }
+}
@@ -0,0 +1,104 @@
+extern crate libproject_haystack_rs;
+
+use std::io::BufRead;
+
+use libproject_haystack_rs::error::*;
+use libproject_haystack_rs::server::user::DEFAULT_ITERATIONS;
+use libproject_haystack_rs::server::{FileUserStore, ScramHash, User, UserStore};
+
+const USAGE: &str = "Manages the users file of a haystack server.
+
+usage: haystack_users <file> list
+       haystack_users <file> add <username> [--hash=SHA-512] [--iterations=N] [role...]
+       haystack_users <file> passwd <username>
+       haystack_users <file> roles <username> [role...]
+       haystack_users <file> remove <username>
+
+Passwords are read from the first line of stdin. The hash and iterations of a user must match
+the scram_hash and scram_iterations of the server for them to log in with SCRAM.";
+
+fn read_password() -> HaystackResult<String> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+
+    let password = line.trim_end_matches(['\r', '\n']).to_string();
+
+    if password.is_empty() {
+        return Err(HaystackError::GeneralError("Empty password".to_string()));
+    }
+
+    Ok(password)
+}
+
+fn existing(store: &FileUserStore, username: &str) -> HaystackResult<User> {
+    store.get(username)?.ok_or_else(|| HaystackError::GeneralError(format!("No user {}", username)))
+}
+
+fn run(args: &[String]) -> HaystackResult<()> {
+    let (path, command) = match args {
+        [path, command, ..] => (path, command.as_str()),
+        _ => return Err(HaystackError::GeneralError(USAGE.to_string())),
+    };
+
+    let store = FileUserStore::open(path)?;
+    let username = args.get(2).map(|s| s.as_str());
+    let (options, roles): (Vec<String>, Vec<String>) = args.iter().skip(3).cloned().partition(|arg| arg.starts_with("--"));
+
+    let mut hash = ScramHash::default();
+    let mut iterations = DEFAULT_ITERATIONS;
+
+    for option in options {
+        match option.split_once('=') {
+            Some(("--hash", name)) => {
+                hash = ScramHash::from_name(name).ok_or_else(|| HaystackError::GeneralError(format!("Unsupported hash {}", name)))?;
+            },
+            Some(("--iterations", n)) => {
+                iterations = n.parse().map_err(|_| HaystackError::GeneralError(format!("Invalid iterations {}", n)))?;
+            },
+            _ => return Err(HaystackError::GeneralError(USAGE.to_string())),
+        }
+    }
+
+    match (command, username) {
+        ("list", None) => {
+            for username in store.usernames()? {
+                let user = existing(&store, &username)?;
+                println!("{} {}", user.username, user.roles.join(","));
+            }
+        },
+        ("add", Some(username)) => {
+            if store.get(username)?.is_some() {
+                return Err(HaystackError::GeneralError(format!("User {} already exists", username)));
+            }
+
+            store.put(User::new_with_hash(username, &read_password()?, hash, iterations, roles)?)?;
+        },
+        ("passwd", Some(username)) => {
+            let mut user = existing(&store, username)?;
+            user.set_password(&read_password()?)?;
+            store.put(user)?;
+        },
+        ("roles", Some(username)) => {
+            let mut user = existing(&store, username)?;
+            user.roles = roles;
+            store.put(user)?;
+        },
+        ("remove", Some(username)) => {
+            if !store.remove(username)? {
+                return Err(HaystackError::GeneralError(format!("No user {}", username)));
+            }
+        },
+        _ => return Err(HaystackError::GeneralError(USAGE.to_string())),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,477 @@
+//! JSON encodings of haystack values.
+//!
+//! Two encodings are supported. Haystack 3 JSON encodes scalars other than strings, numbers
+//! and bools as strings with a type prefix, ie `"n:72.1 °F"` or `"r:someTemp"`. Hayson, the
+//! Haystack 4 JSON encoding, uses objects with a `_kind`, ie
+//! `{"_kind": "number", "val": 72.1, "unit": "°F"}`.
+//!
+//! DateTimes are encoded in UTC like zinc.
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, SecondsFormat, Utc};
+use serde_json::{Map, Number, Value};
+
+use crate::error::*;
+use crate::hval::HVal;
+use crate::token::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Json,
+    Hayson,
+}
+
+/// Encodes a Token, Val, Dict, List or Grid as Haystack 3 JSON.
+pub fn to_json(hval: &dyn HVal) -> Value {
+    encode(hval, Encoding::Json)
+}
+
+/// Encodes a Token, Val, Dict, List or Grid as Hayson.
+pub fn to_hayson(hval: &dyn HVal) -> Value {
+    encode(hval, Encoding::Hayson)
+}
+
+/// Decodes a grid encoded as Haystack 3 JSON.
+pub fn grid_from_json(s: &str) -> HaystackResult<Grid> {
+    decode_grid_str(s, Encoding::Json)
+}
+
+/// Decodes a grid encoded as Hayson.
+pub fn grid_from_hayson(s: &str) -> HaystackResult<Grid> {
+    decode_grid_str(s, Encoding::Hayson)
+}
+
+fn kind(name: &str, val: Option<Value>) -> Value {
+    let mut obj = Map::new();
+    obj.insert("_kind".into(), Value::String(name.into()));
+
+    if let Some(val) = val {
+        obj.insert("val".into(), val);
+    }
+
+    Value::Object(obj)
+}
+
+// DateTimes without a timezone name are encoded in UTC
+fn tz_name(tz: &Option<String>) -> &str {
+    tz.as_deref().unwrap_or("UTC")
+}
+
+fn datetime_str(dt: &DateTime<FixedOffset>, tz: &Option<String>) -> String {
+    match tz.as_deref() {
+        Some(name) if name != "UTC" => dt.to_rfc3339_opts(SecondsFormat::Millis, true),
+        _ => dt.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true),
+    }
+}
+
+fn encode_token(token: &Token, encoding: Encoding) -> Value {
+    let json = encoding == Encoding::Json;
+
+    match token {
+        Token::Empty | Token::Null => Value::Null,
+        Token::Bool(b) => Value::Bool(*b),
+        Token::Marker if json => Value::String("m:".into()),
+        Token::Marker => kind("marker", None),
+        Token::NA if json => Value::String("z:".into()),
+        Token::NA => kind("na", None),
+        Token::Remove if json => Value::String("x:".into()),
+        Token::Remove => kind("remove", None),
+
+        Token::Inf | Token::InfNeg | Token::NaN | Token::Number(_, _) => {
+            let (val, unit) = match token {
+                Token::Inf => ("INF".to_string(), ""),
+                Token::InfNeg => ("-INF".to_string(), ""),
+                Token::NaN => ("NaN".to_string(), ""),
+                Token::Number(n, unit) if n.number.is_infinite() => (if n.number > 0.0 { "INF" } else { "-INF" }.to_string(), unit.as_str()),
+                Token::Number(n, unit) if n.number.is_nan() => ("NaN".to_string(), unit.as_str()),
+                Token::Number(n, unit) => (n.to_string(), unit.as_str()),
+                _ => unreachable!(),
+            };
+
+            if json {
+                return Value::String(if unit.is_empty() { format!("n:{}", val) } else { format!("n:{} {}", val, unit) });
+            }
+
+            let number = match token {
+                Token::Number(n, _) => Number::from_f64(n.number).map(Value::Number),
+                _ => None,
+            }.unwrap_or(Value::String(val));
+
+            if unit.is_empty() && number.is_number() {
+                return number;
+            }
+
+            let mut obj = kind("number", Some(number));
+            if !unit.is_empty() {
+                obj["unit"] = Value::String(unit.into());
+            }
+            obj
+        },
+
+        Token::Ref(id, dis) => {
+            let id = id.strip_prefix('@').unwrap_or(id);
+
+            match (json, dis) {
+                (true, Some(dis)) => Value::String(format!("r:{} {}", id, dis)),
+                (true, None) => Value::String(format!("r:{}", id)),
+                (false, dis) => {
+                    let mut obj = kind("ref", Some(Value::String(id.into())));
+                    if let Some(dis) = dis {
+                        obj["dis"] = Value::String(dis.clone());
+                    }
+                    obj
+                },
+            }
+        },
+
+        // Strings that look like a prefixed value need the s: prefix
+        Token::EscapedString(s) if json && s.chars().nth(1) == Some(':') => Value::String(format!("s:{}", s)),
+        Token::EscapedString(s) | Token::Id(s) | Token::Ver(s) => Value::String(s.clone()),
+
+        Token::Date(d) if json => Value::String(format!("d:{}", d.format("%Y-%m-%d"))),
+        Token::Date(d) => kind("date", Some(Value::String(d.format("%Y-%m-%d").to_string()))),
+        Token::Time(t) if json => Value::String(format!("h:{}", t.format("%H:%M:%S%.f"))),
+        Token::Time(t) => kind("time", Some(Value::String(t.format("%H:%M:%S%.f").to_string()))),
+
+        Token::DateTime(dt, tz) if json => Value::String(format!("t:{} {}", datetime_str(dt, tz), tz_name(tz))),
+        Token::DateTime(dt, tz) => {
+            let mut obj = kind("dateTime", Some(Value::String(datetime_str(dt, tz))));
+            obj["tz"] = Value::String(tz_name(tz).into());
+            obj
+        },
+
+        Token::Uri(u) if json => Value::String(format!("u:{}", u)),
+        Token::Uri(u) => kind("uri", Some(Value::String(u.clone()))),
+        Token::Symbol(s) if json => Value::String(format!("y:{}", s)),
+        Token::Symbol(s) => kind("symbol", Some(Value::String(s.clone()))),
+
+        Token::NL | Token::Comma => Value::Null,
+    }
+}
+
+fn encode_tag(val: Option<&Val>, encoding: Encoding) -> Value {
+    match val {
+        Some(val) => encode(&*val.hval, encoding),
+        None => encode_token(&Token::Marker, encoding),
+    }
+}
+
+fn encode_grid(grid: &Grid, encoding: Encoding) -> Value {
+    let mut meta = Map::new();
+
+    if let Token::Ver(ver) = &grid.grid_meta.version {
+        meta.insert("ver".into(), Value::String(ver.clone()));
+    }
+
+    if let Some(tags) = &grid.grid_meta.metadata {
+        for tag in tags.iter() {
+            meta.insert(tag.get_id(), encode_tag(tag.value.as_ref(), encoding));
+        }
+    }
+
+    let names: Vec<String> = (0..grid.cols.len()).map(|i| grid.cols[i].get_id_as_str().unwrap_or_default()).collect();
+
+    let cols: Vec<Value> = (0..grid.cols.len()).map(|i| {
+        let mut col_meta = Map::new();
+
+        if let Some(tags) = &grid.cols[i].tags {
+            for tag in tags.iter() {
+                col_meta.insert(tag.get_id(), encode_tag(tag.value.as_ref(), encoding));
+            }
+        }
+
+        let mut col = Map::new();
+
+        // Haystack 3 JSON puts the column meta alongside the name
+        match encoding {
+            Encoding::Json => col = col_meta,
+            Encoding::Hayson if !col_meta.is_empty() => { col.insert("meta".into(), Value::Object(col_meta)); },
+            Encoding::Hayson => (),
+        }
+
+        col.insert("name".into(), Value::String(names[i].clone()));
+        Value::Object(col)
+    }).collect();
+
+    let rows: Vec<Value> = (0..grid.rows.len()).map(|r| {
+        let row = &grid.rows[r];
+
+        Value::Object((0..row.len().min(names.len()))
+            .filter(|&i| !matches!(row[i].cast_to_type_ref::<Token>(), Some(Token::Empty) | Some(Token::Null)))
+            .map(|i| (names[i].clone(), encode(&*row[i].hval, encoding)))
+            .collect())
+    }).collect();
+
+    let mut obj = Map::new();
+
+    if encoding == Encoding::Hayson {
+        obj.insert("_kind".into(), Value::String("grid".into()));
+    }
+
+    obj.insert("meta".into(), Value::Object(meta));
+    obj.insert("cols".into(), Value::Array(cols));
+    obj.insert("rows".into(), Value::Array(rows));
+
+    Value::Object(obj)
+}
+
+fn encode(hval: &dyn HVal, encoding: Encoding) -> Value {
+    if let Some(token) = hval.downcast_ref::<Token>() {
+        encode_token(token, encoding)
+    }
+    else if let Some(val) = hval.downcast_ref::<Val>() {
+        encode(&*val.hval, encoding)
+    }
+    else if let Some(dict) = hval.downcast_ref::<Dict>() {
+        let mut obj: Map<String, Value> = dict.iter().map(|(name, val)| (name.clone(), encode_tag(val, encoding))).collect();
+
+        if encoding == Encoding::Hayson {
+            obj.insert("_kind".into(), Value::String("dict".into()));
+        }
+
+        Value::Object(obj)
+    }
+    else if let Some(list) = hval.downcast_ref::<List>() {
+        Value::Array(list.iter().map(|val| encode(&*val.hval, encoding)).collect())
+    }
+    else if let Some(grid) = hval.downcast_ref::<Grid>() {
+        encode_grid(grid, encoding)
+    }
+    else {
+        Value::Null
+    }
+}
+
+fn invalid(value: &Value) -> HaystackError {
+    HaystackError::GeneralError(format!("Invalid JSON value {}", value))
+}
+
+fn parse_number(val: &str, unit: &str) -> Option<Token> {
+    let n = match val {
+        "INF" => f64::INFINITY,
+        "-INF" => f64::NEG_INFINITY,
+        "NaN" => f64::NAN,
+        _ => val.parse::<f64>().ok()?,
+    };
+
+    Some(Token::Number(ZincNumber::new(n), unit.to_string()))
+}
+
+fn parse_datetime(s: &str) -> Option<Token> {
+    // The offset time and then the timezone name
+    let mut parts = s.split(' ');
+    let dt = DateTime::parse_from_rfc3339(parts.next()?).ok()?;
+    Some(Token::DateTime(dt, parts.next().map(|tz| tz.to_string())))
+}
+
+/// Decodes a Haystack 3 JSON string, which may have a type prefix.
+fn decode_json_str(s: &str) -> Option<Token> {
+    let mut chars = s.chars();
+
+    let (prefix, rest) = match (chars.next(), chars.next()) {
+        (Some(prefix), Some(':')) => (prefix, &s[prefix.len_utf8() + 1..]),
+        _ => return Some(Token::EscapedString(s.to_string())),
+    };
+
+    match prefix {
+        'm' => Some(Token::Marker),
+        'z' => Some(Token::NA),
+        'x' => Some(Token::Remove),
+        's' => Some(Token::EscapedString(rest.to_string())),
+        'n' => {
+            let mut parts = rest.splitn(2, ' ');
+            parse_number(parts.next()?, parts.next().unwrap_or(""))
+        },
+        'r' => {
+            let mut parts = rest.splitn(2, ' ');
+            Some(Token::Ref(format!("@{}", parts.next()?), parts.next().map(|dis| dis.to_string())))
+        },
+        'd' => NaiveDate::parse_from_str(rest, "%Y-%m-%d").ok().map(Token::Date),
+        'h' => NaiveTime::parse_from_str(rest, "%H:%M:%S%.f").ok().map(Token::Time),
+        't' => parse_datetime(rest),
+        'u' => Some(Token::Uri(rest.to_string())),
+        'y' => Some(Token::Symbol(rest.to_string())),
+        // Coords, bins and anything else are left as strings
+        _ => Some(Token::EscapedString(s.to_string())),
+    }
+}
+
+/// Decodes a Hayson object with a `_kind`.
+fn decode_kind(kind: &str, obj: &Map<String, Value>) -> Option<Token> {
+    let val = obj.get("val");
+    let val_str = val.and_then(Value::as_str);
+
+    match kind {
+        "marker" => Some(Token::Marker),
+        "na" => Some(Token::NA),
+        "remove" => Some(Token::Remove),
+        "number" => {
+            let unit = obj.get("unit").and_then(Value::as_str).unwrap_or("");
+
+            match val? {
+                Value::Number(n) => Some(Token::Number(ZincNumber::new(n.as_f64()?), unit.to_string())),
+                Value::String(s) => parse_number(s, unit),
+                _ => None,
+            }
+        },
+        "ref" => Some(Token::Ref(format!("@{}", val_str?), obj.get("dis").and_then(Value::as_str).map(|dis| dis.to_string()))),
+        "date" => NaiveDate::parse_from_str(val_str?, "%Y-%m-%d").ok().map(Token::Date),
+        "time" => NaiveTime::parse_from_str(val_str?, "%H:%M:%S%.f").ok().map(Token::Time),
+        "dateTime" => match parse_datetime(val_str?)? {
+            Token::DateTime(dt, _) => Some(Token::DateTime(dt, obj.get("tz").and_then(Value::as_str).map(|tz| tz.to_string()))),
+            _ => None,
+        },
+        "uri" => Some(Token::Uri(val_str?.to_string())),
+        "symbol" => Some(Token::Symbol(val_str?.to_string())),
+        _ => None,
+    }
+}
+
+fn token_val(token: Token) -> Val {
+    Val::new(Box::new(token))
+}
+
+fn decode_tags<'a>(obj: impl Iterator<Item = (&'a String, &'a Value)>, encoding: Encoding) -> HaystackResult<Vec<Tag>> {
+    obj.map(|(name, value)| {
+        let val = decode(value, encoding)?;
+
+        // Markers are tags without a value
+        Ok(match val.cast_to_type_ref::<Token>() {
+            Some(Token::Marker) => Tag::new_marker(name),
+            _ => Tag::new_from_val(Token::Id(name.clone()), Some(val)),
+        })
+    }).collect()
+}
+
+fn is_grid(obj: &Map<String, Value>) -> bool {
+    obj.get("meta").and_then(|m| m.get("ver")).is_some() && obj.contains_key("cols") && obj.contains_key("rows")
+}
+
+fn decode(value: &Value, encoding: Encoding) -> HaystackResult<Val> {
+    let val = match value {
+        Value::Null => token_val(Token::Null),
+        Value::Bool(b) => token_val(Token::Bool(*b)),
+        Value::Number(n) => token_val(Token::Number(ZincNumber::new(n.as_f64().ok_or_else(|| invalid(value))?), "".into())),
+        Value::String(s) if encoding == Encoding::Json => token_val(decode_json_str(s).ok_or_else(|| invalid(value))?),
+        Value::String(s) => token_val(Token::EscapedString(s.clone())),
+        Value::Array(vals) => Val::new(Box::new(List::new(vals.iter().map(|v| decode(v, encoding)).collect::<HaystackResult<Vec<Val>>>()?))),
+
+        Value::Object(obj) => match obj.get("_kind").and_then(Value::as_str) {
+            Some("grid") => Val::new(Box::new(decode_grid(obj, encoding)?)),
+            Some("dict") => Val::new(Box::new(Dict::new(&decode_tags(obj.iter().filter(|(k, _)| *k != "_kind"), encoding)?))),
+            Some(kind) if encoding == Encoding::Hayson => token_val(decode_kind(kind, obj).ok_or_else(|| invalid(value))?),
+            _ if is_grid(obj) => Val::new(Box::new(decode_grid(obj, encoding)?)),
+            _ => Val::new(Box::new(Dict::new(&decode_tags(obj.iter(), encoding)?))),
+        },
+    };
+
+    Ok(val)
+}
+
+fn decode_grid(obj: &Map<String, Value>, encoding: Encoding) -> HaystackResult<Grid> {
+    let empty = Map::new();
+    let meta = obj.get("meta").and_then(Value::as_object).unwrap_or(&empty);
+
+    let ver = meta.get("ver").and_then(Value::as_str).unwrap_or("3.0");
+    let meta_tags = decode_tags(meta.iter().filter(|(k, _)| *k != "ver" && *k != "_kind"), encoding)?;
+
+    let grid_meta = GridMeta::new(Token::Ver(ver.to_string()), if meta_tags.is_empty() { None } else { Some(Tags::new(&meta_tags)) });
+
+    let mut names = vec![];
+    let mut cols = vec![];
+
+    for col in obj.get("cols").and_then(Value::as_array).ok_or("Grid has no cols")? {
+        let col = col.as_object().ok_or_else(|| invalid(col))?;
+        let name = col.get("name").and_then(Value::as_str).ok_or("Grid col has no name")?;
+
+        let col_tags = match encoding {
+            Encoding::Json => decode_tags(col.iter().filter(|(k, _)| *k != "name"), encoding)?,
+            Encoding::Hayson => match col.get("meta").and_then(Value::as_object) {
+                Some(col_meta) => decode_tags(col_meta.iter().filter(|(k, _)| *k != "_kind"), encoding)?,
+                None => vec![],
+            },
+        };
+
+        names.push(name.to_string());
+        cols.push(Col::new(Token::Id(name.to_string()), if col_tags.is_empty() { None } else { Some(Tags::new(&col_tags)) }));
+    }
+
+    let mut rows = vec![];
+
+    for row in obj.get("rows").and_then(Value::as_array).ok_or("Grid has no rows")? {
+        let row = row.as_object().ok_or_else(|| invalid(row))?;
+
+        let cells = names.iter().map(|name| match row.get(name) {
+            Some(value) => decode(value, encoding),
+            None => Ok(token_val(Token::Empty)),
+        }).collect::<HaystackResult<Vec<Val>>>()?;
+
+        rows.push(Row::new(cells));
+    }
+
+    Ok(Grid::new(grid_meta, Cols::new(cols), Rows::new(rows)))
+}
+
+fn decode_grid_str(s: &str, encoding: Encoding) -> HaystackResult<Grid> {
+    let value: Value = serde_json::from_str(s)?;
+
+    match &value {
+        Value::Object(obj) => decode_grid(obj, encoding),
+        _ => Err(invalid(&value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zinc_tokenizer::grid;
+
+    #[test]
+    fn json_test() {
+        let (_, g) = grid("ver:\"3.0\" dis:\"Temps\"\nid,temp unit:\"°F\",his,ts\n@a \"Alpha\",72.1°F,M,2012-10-01T05:00:00Z UTC\n@b,,,\n").unwrap();
+
+        let json = to_json(&g);
+        assert_eq!(json["meta"]["ver"], "3.0");
+        assert_eq!(json["cols"][1], serde_json::json!({"name": "temp", "unit": "°F"}));
+        assert_eq!(json["rows"][0], serde_json::json!({"id": "r:a Alpha", "temp": "n:72.1 °F", "his": "m:", "ts": "t:2012-10-01T05:00:00.000Z UTC"}));
+        assert_eq!(json["rows"][1], serde_json::json!({"id": "r:b"}));
+
+        let decoded = grid_from_json(&json.to_string()).unwrap();
+        assert_eq!(decoded.cols.len(), 4);
+        assert_eq!(decoded.rows[0][0].cast_to_type::<Token>(), Some(Token::Ref("@a".into(), Some("Alpha".into()))));
+        assert_eq!(decoded.rows[0][1].cast_to_type::<Token>(), Some(Token::Number(ZincNumber::new(72.1), "°F".into())));
+        assert_eq!(decoded.rows[1][1].cast_to_type::<Token>(), Some(Token::Empty));
+        assert_eq!(to_json(&decoded), json);
+
+        assert_eq!(to_json(&Token::EscapedString("a:b".into())), "s:a:b");
+        assert_eq!(decode_json_str("s:a:b"), Some(Token::EscapedString("a:b".into())));
+        assert_eq!(decode_json_str("n:INF"), Some(Token::Number(ZincNumber::new(f64::INFINITY), "".into())));
+        assert!(grid_from_json("[1, 2]").is_err());
+        assert!(grid_from_json("{\"meta\": {\"ver\": \"3.0\"}, \"cols\": [{\"name\": \"x\"}], \"rows\": [{\"x\": \"d:2012-13-45\"}]}").is_err());
+
+        // DateTimes keep their timezone
+        let ny = decode_json_str("t:2012-10-01T01:00:00.000-04:00 New_York").unwrap();
+        assert_eq!(ny.to_zinc(), "2012-10-01T01:00:00.000-04:00 New_York");
+        assert_eq!(to_json(&ny), "t:2012-10-01T01:00:00.000-04:00 New_York");
+        assert_eq!(to_hayson(&ny), serde_json::json!({"_kind": "dateTime", "val": "2012-10-01T01:00:00.000-04:00", "tz": "New_York"}));
+    }
+
+    #[test]
+    fn hayson_test() {
+        let (_, g) = grid("ver:\"3.0\"\nts,v0 id:@a,flag\n2012-10-01T05:00:00Z UTC,72.1°F,M\n").unwrap();
+
+        let hayson = to_hayson(&g);
+        assert_eq!(hayson["_kind"], "grid");
+        assert_eq!(hayson["cols"][1], serde_json::json!({"name": "v0", "meta": {"id": {"_kind": "ref", "val": "a"}}}));
+        assert_eq!(hayson["rows"][0], serde_json::json!({
+            "ts": {"_kind": "dateTime", "val": "2012-10-01T05:00:00.000Z", "tz": "UTC"},
+            "v0": {"_kind": "number", "val": 72.1, "unit": "°F"},
+            "flag": {"_kind": "marker"},
+        }));
+
+        let decoded = grid_from_hayson(&hayson.to_string()).unwrap();
+        assert_eq!(to_hayson(&decoded), hayson);
+
+        assert_eq!(to_hayson(&Token::Number(ZincNumber::new(3.0), "".into())), serde_json::json!(3.0));
+        assert_eq!(to_hayson(&Token::NaN), serde_json::json!({"_kind": "number", "val": "NaN"}));
+        assert!(grid_from_hayson("{\"_kind\": \"grid\", \"meta\": {\"ver\": \"3.0\"}, \"cols\": [{\"name\": \"a\"}], \"rows\": [{\"a\": {\"_kind\": \"bogus\"}}]}").is_err());
+    }
+}
@@ -28,12 +28,17 @@ extern crate array_tool;
 pub mod error;
 pub mod token;
 pub mod hval;
+pub mod json;
 pub mod zinc_tokenizer;
 pub mod server;
 pub mod filter;
 pub mod prelude;
 pub mod filter_tokenizer;
 pub mod filter_shunting_yard;
+pub mod filter_ast;
+pub mod defs;
+pub mod units;
+pub mod timezone;
 
 #[cfg(test)]
 mod tests {
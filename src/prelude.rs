@@ -2,5 +2,7 @@ pub use crate::hval::HVal;
 pub use crate::error::*;
 pub use crate::token::*;
 pub use crate::server::*;
-pub use crate::filter::{RefTag, RefTags, filter_eval_str, get_tag_value_for_first_tag_with_id};
-pub use crate::zinc_tokenizer::{grid, date_range_to_token};
\ No newline at end of file
+pub use crate::filter::{RefTag, RefTags, filter_eval_str, filter_eval_str_with_defs, filter_eval_str_referenced_by, filter_eval_str_referenced_by_with_index, ReverseRefIndex, get_tag_value_for_first_tag_with_id};
+pub use crate::defs::Namespace;
+pub use crate::filter_ast::FilterNode;
+pub use crate::zinc_tokenizer::{grid, date_range_to_token, date_range_to_token_in_tz};
\ No newline at end of file
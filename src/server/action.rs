@@ -0,0 +1,182 @@
+//! The invokeAction op and the actions an application registers for it.
+//!
+//! An action is an async function taking the target entity and the arguments of the request,
+//! and returning a grid. Actions that are not registered are passed on to
+//! `HaystackDatabase::invoke_action`.
+//!
+//! ```rust,no_run
+//! use libproject_haystack_rs::prelude::*;
+//!
+//! let mut actions = Actions::new();
+//!
+//! actions.register("restart", |(id, _tags): RefTag, args: Dict| async move {
+//!     let delay = args.get_value::<Token>("delay");
+//!     println!("restarting {} after {:?}", id, delay);
+//!     Ok(Grid::empty())
+//! });
+//! ```
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::filter::RefTag;
+use crate::hval::HVal;
+use crate::token::*;
+
+use super::database::HaystackDatabase;
+use super::auth::Identity;
+use super::format::Format;
+use super::policy::{Policy, PolicyDatabase};
+use super::{bad_request, error_reply, meta_value};
+
+pub type ActionFuture = Pin<Box<dyn Future<Output = HaystackResult<Grid>> + Send>>;
+pub type ActionHandler = Box<dyn Fn(RefTag, Dict) -> ActionFuture + Send + Sync>;
+
+/// The actions of a server, by name.
+#[derive(Default)]
+pub struct Actions {
+    handlers: HashMap<String, ActionHandler>,
+}
+
+impl Actions {
+    pub fn new() -> Self {
+        Actions::default()
+    }
+
+    /// Registers `handler` as the action `name`, replacing any action with the same name.
+    pub fn register<F, Fut>(&mut self, name: &str, handler: F)
+        where F: Fn(RefTag, Dict) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HaystackResult<Grid>> + Send + 'static {
+
+        self.handlers.insert(name.to_string(), Box::new(move |entity, args| Box::pin(handler(entity, args))));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+
+    /// The names of the registered actions, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.handlers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Runs the action `name` on `entity`, `None` if there is no such action.
+    pub fn invoke(&self, name: &str, entity: RefTag, args: Dict) -> Option<ActionFuture> {
+        self.handlers.get(name).map(|handler| handler(entity, args))
+    }
+}
+
+/// The tags of the first row of a request grid, leaving out empty cells.
+fn request_args(grid: &Grid) -> Vec<Tag> {
+    if grid.rows.len() == 0 {
+        return vec![];
+    }
+
+    (0..grid.cols.len()).filter_map(|i| {
+        let name = grid.cols[i].get_id_as_str()?;
+
+        match grid.rows[0][i].cast_to_type::<Token>()? {
+            Token::Empty | Token::Null => None,
+            Token::Marker => Some(Tag::new_marker(&name)),
+            value => Some(Tag::new_from_token(Token::Id(name), value)),
+        }
+    }).collect()
+}
+
+async fn invoke<D: HaystackDatabase>(actions: &Actions, db: &D, id: &Token, action: &str, args: Vec<Tag>) -> HaystackResult<Grid> {
+    let entity = db.read_by_ids(std::slice::from_ref(id)).await?.pop().flatten()
+        .ok_or_else(|| HaystackError::UnknownRecord(id.to_zinc()))?;
+
+    match actions.invoke(action, entity, Dict::new(&args)) {
+        Some(future) => future.await,
+        None => db.invoke_action(id, action, args).await,
+    }
+}
+
+// Request: the id of the target entity and the action name in the metadata, and a single row
+// with the arguments of the action.
+//
+// ver:"3.0" id:@ahu action:"restart"
+// delay
+// 10s
+//
+// Response: the grid returned by the action, or an error grid.
+pub async fn invoke_action<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    actions: Arc<Actions>,
+    policy: Arc<Policy>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    let db = PolicyDatabase::new(db, policy, &identity);
+
+    let request = match request {
+        Some(g) => g,
+        None => return Ok(bad_request(&format, "Missing request grid")),
+    };
+
+    let (id, action) = match (meta_value(&request, "id"), meta_value(&request, "action")) {
+        (Some(id @ Token::Ref(_, _)), Some(Token::EscapedString(action))) => (id, action),
+        _ => return Ok(bad_request(&format, "Request must have id and action in the metadata")),
+    };
+
+    match invoke(&actions, &db, &id, &action, request_args(&request)).await {
+        Ok(grid) => Ok(format.reply(&grid, http::StatusCode::OK)),
+        Err(e) => {
+            debug!("invokeAction {} failed: {}", action, e);
+            Ok(error_reply(&format, &e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::MemoryDatabase;
+    use crate::zinc_tokenizer::grid;
+    use futures::executor::block_on;
+
+    #[test]
+    fn invoke_action_test() {
+        let db = MemoryDatabase::new(vec![
+            (Token::Ref("@ahu".to_string(), None), vec![Tag::new_marker("equip")]),
+        ]);
+
+        let mut actions = Actions::new();
+
+        actions.register("echo", |(id, _), args: Dict| async move {
+            let delay = args.get_value::<Token>("delay").ok_or("delay is required")?;
+
+            let cols = Cols::new(vec![Col::new(Token::Id("id".into()), None), Col::new(Token::Id("delay".into()), None)]);
+            let row = Row::new(vec![Val::new(Box::new(id)), Val::new(Box::new(delay))]);
+
+            Ok(Grid::new(GridMeta::new(Token::Ver("3.0".into()), None), cols, Rows::new(vec![row])))
+        });
+
+        assert_eq!(actions.names(), vec!["echo".to_string()]);
+
+        let (_, request) = grid("ver:\"3.0\" id:@ahu action:\"echo\"\ndelay,force\n10s,M\n").unwrap();
+        let args = request_args(&request);
+        assert_eq!(args.len(), 2);
+
+        let ahu = Token::Ref("@ahu".to_string(), None);
+
+        let result = block_on(invoke(&actions, &db, &ahu, "echo", args)).unwrap();
+        assert_eq!(result.to_zinc(), "ver:\"3.0\"\nid,delay\n@ahu,10s");
+
+        assert!(block_on(invoke(&actions, &db, &ahu, "echo", vec![])).is_err());
+
+        // Falls through to the database, which doesn't support actions
+        assert!(matches!(block_on(invoke(&actions, &db, &ahu, "other", vec![])), Err(HaystackError::NotSupported(_))));
+
+        let missing = Token::Ref("@missing".to_string(), None);
+        assert!(matches!(block_on(invoke(&actions, &db, &missing, "echo", vec![])), Err(HaystackError::UnknownRecord(_))));
+    }
+}
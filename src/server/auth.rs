@@ -0,0 +1,504 @@
+//! Bearer auth tokens.
+//!
+//! A successful login is answered with an auth token, a JWT signed with HMAC-SHA256 (HS256)
+//! that names the user, the issuer and when it expires. The first key of a `TokenSigner` signs
+//! new tokens and the others only verify, so a key can be rotated out once the tokens it
+//! signed have expired.
+//!
+//! A token is valid if its signature, issuer and expiry check out, so tokens signed with
+//! configured keys outlive a restart. The close op and administrators can revoke tokens before
+//! they expire. Revocations are kept in memory until the tokens they cover expire, so they do
+//! not outlive a restart.
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use data_encoding::BASE64URL_NOPAD;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::*;
+
+use super::config::ServerConfig;
+use super::scram::{nonce, Handshakes, ScramHash};
+use super::throttle::Throttle;
+use super::user::{User, UserStore, DEFAULT_ITERATIONS, SALT_LEN};
+
+/// Seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// An HMAC key, named by the `kid` header of the tokens it signs.
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SigningKey({})", self.id)
+    }
+}
+
+impl SigningKey {
+    /// The shortest secret accepted, the output size of SHA-256.
+    pub const MIN_SECRET_LEN: usize = 32;
+
+    /// A key with a random secret.
+    pub fn random(id: &str) -> HaystackResult<Self> {
+        let mut bytes = vec![0u8; SigningKey::MIN_SECRET_LEN];
+        OsRng::new().map_err(|e| HaystackError::GeneralError(e.to_string()))?.fill_bytes(&mut bytes);
+
+        Ok(SigningKey { id: id.to_string(), secret: BASE64URL_NOPAD.encode(&bytes) })
+    }
+
+    fn hmac_key(&self) -> ring::hmac::Key {
+        ring::hmac::Key::new(ring::hmac::HMAC_SHA256, self.secret.as_bytes())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    /// The username.
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    /// Unique per token.
+    pub jti: String,
+}
+
+pub struct TokenSigner {
+    keys: Vec<SigningKey>,
+    issuer: String,
+    lifetime: u64,
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> HaystackResult<T> {
+    let bytes = BASE64URL_NOPAD.decode(part.as_bytes()).map_err(|_| HaystackError::AuthError)?;
+    serde_json::from_slice(&bytes).map_err(|_| HaystackError::AuthError)
+}
+
+fn encode_part<T: Serialize>(value: &T) -> HaystackResult<String> {
+    Ok(BASE64URL_NOPAD.encode(&serde_json::to_vec(value)?))
+}
+
+impl TokenSigner {
+    /// Signs with the first of `keys` and verifies with all of them.
+    pub fn new(keys: Vec<SigningKey>, issuer: &str, lifetime: u64) -> HaystackResult<Self> {
+        if keys.is_empty() {
+            return Err(HaystackError::GeneralError("At least one token key is required".to_string()));
+        }
+
+        if let Some(key) = keys.iter().find(|k| k.secret.len() < SigningKey::MIN_SECRET_LEN) {
+            return Err(HaystackError::GeneralError(format!("Token key {} is shorter than {} bytes", key.id, SigningKey::MIN_SECRET_LEN)));
+        }
+
+        Ok(TokenSigner { keys, issuer: issuer.to_string(), lifetime })
+    }
+
+    /// The signer of a config, with a random key if it has none.
+    pub fn from_config(config: &ServerConfig) -> HaystackResult<Self> {
+        let keys = if config.token_keys.is_empty() {
+            warn!("No token keys are configured, auth tokens will not outlive the server");
+            vec![SigningKey::random("default")?]
+        }
+        else {
+            config.token_keys.clone()
+        };
+
+        TokenSigner::new(keys, &config.token_issuer, config.token_lifetime)
+    }
+
+    pub fn issue(&self, username: &str) -> HaystackResult<String> {
+        self.issue_at(username, now())
+    }
+
+    pub fn issue_at(&self, username: &str, now: u64) -> HaystackResult<String> {
+        let key = &self.keys[0];
+
+        let header = Header { alg: "HS256".to_string(), typ: "JWT".to_string(), kid: key.id.clone() };
+        let claims = Claims { iss: self.issuer.clone(), sub: username.to_string(), iat: now, exp: now + self.lifetime, jti: nonce() };
+
+        let signing_input = format!("{}.{}", encode_part(&header)?, encode_part(&claims)?);
+        let signature = ring::hmac::sign(&key.hmac_key(), signing_input.as_bytes());
+
+        Ok(format!("{}.{}", signing_input, BASE64URL_NOPAD.encode(signature.as_ref())))
+    }
+
+    /// The claims of a token signed by one of the keys, with this issuer, that has not expired.
+    pub fn verify(&self, token: &str) -> HaystackResult<Claims> {
+        self.verify_at(token, now())
+    }
+
+    pub fn verify_at(&self, token: &str, now: u64) -> HaystackResult<Claims> {
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let (header, claims, signature) = match parts.as_slice() {
+            [header, claims, signature] => (*header, *claims, *signature),
+            _ => return Err(HaystackError::AuthError),
+        };
+
+        let decoded_header: Header = decode_part(header)?;
+
+        if decoded_header.alg != "HS256" {
+            return Err(HaystackError::AuthError);
+        }
+
+        let key = self.keys.iter().find(|k| k.id == decoded_header.kid).ok_or(HaystackError::AuthError)?;
+        let signature = BASE64URL_NOPAD.decode(signature.as_bytes()).map_err(|_| HaystackError::AuthError)?;
+
+        ring::hmac::verify(&key.hmac_key(), format!("{}.{}", header, claims).as_bytes(), &signature)
+            .map_err(|_| HaystackError::AuthError)?;
+
+        let claims: Claims = decode_part(claims)?;
+
+        if claims.iss != self.issuer || claims.exp <= now {
+            return Err(HaystackError::AuthError);
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Who an auth token was issued to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub username: String,
+    /// The current roles of the user.
+    pub roles: Vec<String>,
+    /// When the token expires, in seconds since the epoch.
+    pub expires: u64,
+    /// The `jti` of the token, which revokes it.
+    pub token_id: String,
+}
+
+impl Identity {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// The authentication state of a server.
+pub struct Auth {
+    pub signer: TokenSigner,
+    pub users: Arc<dyn UserStore>,
+    pub handshakes: Handshakes,
+    pub throttle: Throttle,
+    /// Whether passwords are accepted with the PLAINTEXT scheme.
+    pub plaintext: bool,
+    /// Whether passwords are accepted with HTTP Basic auth.
+    pub basic: bool,
+    /// The hash and iteration count every SCRAM login uses, so accounts cannot be told apart by
+    /// them.
+    pub scram_hash: ScramHash,
+    pub scram_iterations: u32,
+    // jti -> expiry of the tokens revoked before they expired
+    revoked: Mutex<HashMap<String, u64>>,
+    // username -> the tokens of the user issued up to this time are revoked
+    revoked_users: Mutex<HashMap<String, u64>>,
+    // Derives the salts of decoy users
+    decoy_key: ring::hmac::Key,
+}
+
+impl Auth {
+    /// How often `spawn_sweeper` evicts the revocations of expired tokens.
+    pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(signer: TokenSigner, users: Arc<dyn UserStore>) -> Self {
+        Auth {
+            signer,
+            users,
+            handshakes: Handshakes::default(),
+            throttle: Throttle::default(),
+            plaintext: false,
+            basic: false,
+            scram_hash: ScramHash::default(),
+            scram_iterations: DEFAULT_ITERATIONS,
+            revoked: Mutex::new(HashMap::new()),
+            revoked_users: Mutex::new(HashMap::new()),
+            decoy_key: ring::hmac::Key::new(ring::hmac::HMAC_SHA256, nonce().as_bytes()),
+        }
+    }
+
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn with_password_schemes(mut self, plaintext: bool, basic: bool) -> Self {
+        self.plaintext = plaintext;
+        self.basic = basic;
+        self
+    }
+
+    pub fn with_scram(mut self, hash: ScramHash, iterations: u32) -> Self {
+        self.scram_hash = hash;
+        self.scram_iterations = iterations;
+        self
+    }
+
+    /// A stand-in for a user that does not exist, so that logging in as them goes through the
+    /// same steps as a wrong password. The salt is the same every time for the same username.
+    pub fn decoy_user(&self, username: &str) -> User {
+        let tag = |label: &str| ring::hmac::sign(&self.decoy_key, format!("{}\0{}", label, username).as_bytes()).as_ref().to_vec();
+
+        User {
+            username: username.to_string(),
+            hash: self.scram_hash,
+            salt: tag("salt")[..SALT_LEN].to_vec(),
+            iterations: self.scram_iterations,
+            stored_key: tag("stored"),
+            server_key: tag("server"),
+            roles: vec![],
+        }
+    }
+
+    /// The user to log in with SCRAM, `None` if there is no such user or their hash, iteration
+    /// count or salt length differ from the server's, as those would tell the accounts apart.
+    pub fn scram_user(&self, username: &str) -> Option<User> {
+        let user = self.users.get(username).ok().flatten()?;
+
+        if user.hash != self.scram_hash || user.iterations != self.scram_iterations || user.salt.len() != SALT_LEN {
+            warn!("{} cannot log in with SCRAM, their hash or iterations differ from the server's", username);
+            return None;
+        }
+
+        Some(user)
+    }
+
+    /// Issues a token to a user that has authenticated.
+    pub fn login(&self, username: &str) -> HaystackResult<String> {
+        // Tokens issued in the second the user's tokens were revoked are revoked too, so a new
+        // token is dated after it
+        let issued = match self.revoked_users.lock().get(username) {
+            Some(revoked) => now().max(revoked + 1),
+            None => now(),
+        };
+
+        self.signer.issue_at(username, issued)
+    }
+
+    /// Issues a token to a user that sent their password. Unknown users are checked against a
+    /// decoy, so they take as long to fail as a wrong password.
+    pub fn login_with_password(&self, username: &str, password: &str) -> HaystackResult<String> {
+        let known = self.users.get(username)?;
+        let user = known.clone().unwrap_or_else(|| self.decoy_user(username));
+
+        if !user.verify_password(password) || known.is_none() {
+            return Err(HaystackError::AuthError);
+        }
+
+        self.login(username)
+    }
+
+    /// The identity of a valid auth token. Tokens that were revoked, and tokens of users that
+    /// have since been removed, are not valid.
+    pub fn introspect(&self, token: &str) -> HaystackResult<Identity> {
+        let claims = self.signer.verify(token)?;
+
+        if self.revoked.lock().contains_key(&claims.jti) {
+            return Err(HaystackError::AuthError);
+        }
+
+        if self.revoked_users.lock().get(&claims.sub).is_some_and(|revoked| claims.iat <= *revoked) {
+            return Err(HaystackError::AuthError);
+        }
+
+        let user = self.users.get(&claims.sub)?.ok_or(HaystackError::AuthError)?;
+
+        Ok(Identity { username: user.username, roles: user.roles, expires: claims.exp, token_id: claims.jti })
+    }
+
+    /// Revokes the token with `token_id` that expires at `expires`, returning whether it had not
+    /// been revoked already.
+    pub fn revoke(&self, token_id: &str, expires: u64) -> bool {
+        self.revoked.lock().insert(token_id.to_string(), expires).is_none()
+    }
+
+    /// Revokes all the tokens issued to a user until now, returning the time they were revoked.
+    pub fn revoke_user(&self, username: &str) -> u64 {
+        let revoked = now();
+        self.revoked_users.lock().insert(username.to_string(), revoked);
+        revoked
+    }
+
+    /// Forgets the revocations of the tokens that expired by `now`, the handshakes that timed out
+    /// and the login failures that were forgotten, returning how many revocations were forgotten.
+    pub fn sweep(&self, now: u64) -> usize {
+        self.handshakes.sweep();
+        self.throttle.sweep(Instant::now());
+
+        let mut revoked = self.revoked.lock();
+        let mut revoked_users = self.revoked_users.lock();
+        let before = revoked.len() + revoked_users.len();
+
+        // Every token issued before a user's revocation expires within a lifetime of it
+        revoked.retain(|_, expires| *expires > now);
+        revoked_users.retain(|_, revoked| *revoked + self.signer.lifetime > now);
+
+        before - revoked.len() - revoked_users.len()
+    }
+
+    /// Sweeps `auth` every `interval` on the tokio runtime, until `auth` is dropped.
+    pub fn spawn_sweeper(auth: &Arc<Auth>, interval: Duration) {
+        let auth: Weak<Auth> = Arc::downgrade(auth);
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+            loop {
+                ticks.tick().await;
+
+                match auth.upgrade() {
+                    Some(auth) => {
+                        let forgotten = auth.sweep(now());
+
+                        if forgotten > 0 {
+                            debug!("Forgot {} revocations of expired auth tokens", forgotten);
+                        }
+                    },
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::user::{MemoryUserStore, User};
+
+    fn key(id: &str) -> SigningKey {
+        SigningKey { id: id.to_string(), secret: format!("{}-0123456789abcdef0123456789abcdef", id) }
+    }
+
+    #[test]
+    fn token_test() {
+        let signer = TokenSigner::new(vec![key("a")], "haystack", 3600).unwrap();
+        let token = signer.issue_at("user", 1000).unwrap();
+
+        let claims = signer.verify_at(&token, 2000).unwrap();
+        assert_eq!(claims.sub, "user");
+        assert_eq!(claims.exp, 4600);
+        assert_ne!(claims.jti, signer.verify_at(&signer.issue_at("user", 1000).unwrap(), 2000).unwrap().jti);
+
+        // Expired
+        assert!(signer.verify_at(&token, 4600).is_err());
+
+        // Tampered claims
+        let parts: Vec<&str> = token.split('.').collect();
+        let claims = BASE64URL_NOPAD.encode(br#"{"iss":"haystack","sub":"admin","iat":1000,"exp":4600,"jti":"x"}"#);
+        assert!(signer.verify_at(&format!("{}.{}.{}", parts[0], claims, parts[2]), 2000).is_err());
+
+        // Unsigned
+        let none = BASE64URL_NOPAD.encode(br#"{"alg":"none","typ":"JWT","kid":"a"}"#);
+        assert!(signer.verify_at(&format!("{}.{}.", none, parts[1]), 2000).is_err());
+
+        // Another issuer
+        let other = TokenSigner::new(vec![key("a")], "other", 3600).unwrap();
+        assert!(other.verify_at(&token, 2000).is_err());
+
+        // Rotated: the old key still verifies but no longer signs
+        let rotated = TokenSigner::new(vec![key("b"), key("a")], "haystack", 3600).unwrap();
+        assert!(rotated.verify_at(&token, 2000).is_ok());
+        assert!(signer.verify_at(&rotated.issue_at("user", 1000).unwrap(), 2000).is_err());
+
+        assert!(TokenSigner::new(vec![], "haystack", 3600).is_err());
+        assert!(TokenSigner::new(vec![SigningKey { id: "short".to_string(), secret: "secret".to_string() }], "haystack", 3600).is_err());
+        assert_eq!(format!("{:?}", key("a")), "SigningKey(a)");
+    }
+
+    #[test]
+    fn introspect_test() {
+        let users = Arc::new(MemoryUserStore::new(vec![User::new("user", "pencil", 4096, vec!["operator".to_string()]).unwrap()]));
+        let auth = Auth::new(TokenSigner::new(vec![key("a")], "haystack", 3600).unwrap(), users.clone());
+
+        let token = auth.login("user").unwrap();
+        let identity = auth.introspect(&token).unwrap();
+        assert_eq!(identity.username, "user");
+        assert_eq!(identity.roles, vec!["operator".to_string()]);
+
+        assert!(auth.introspect("PKLivoIgkH390hiKHOAutagi2Emfd5").is_err());
+
+        // Still valid after a restart with the same keys
+        let restarted = Auth::new(TokenSigner::new(vec![key("a")], "haystack", 3600).unwrap(), users.clone());
+        assert_eq!(restarted.introspect(&token).unwrap().username, "user");
+
+        users.remove("user").unwrap();
+        assert!(auth.introspect(&token).is_err());
+    }
+
+    #[test]
+    fn login_with_password_test() {
+        let users = Arc::new(MemoryUserStore::new(vec![User::new("user", "pencil", 4096, vec![]).unwrap()]));
+        let auth = Auth::new(TokenSigner::new(vec![key("a")], "haystack", 3600).unwrap(), users);
+
+        let token = auth.login_with_password("user", "pencil").unwrap();
+        assert_eq!(auth.introspect(&token).unwrap().username, "user");
+
+        assert!(auth.login_with_password("user", "crayon").is_err());
+        assert!(auth.login_with_password("nobody", "pencil").is_err());
+    }
+
+    #[test]
+    fn decoy_user_test() {
+        let auth = Auth::new(TokenSigner::new(vec![key("a")], "haystack", 3600).unwrap(), Arc::new(MemoryUserStore::default()));
+
+        let decoy = auth.decoy_user("nobody");
+        assert_eq!(decoy, auth.decoy_user("nobody"));
+        assert_ne!(decoy.salt, auth.decoy_user("somebody").salt);
+        assert_eq!(decoy.salt.len(), SALT_LEN);
+        assert_eq!(decoy.iterations, DEFAULT_ITERATIONS);
+
+        let auth = auth.with_scram(ScramHash::Sha512, 8192);
+        assert_eq!(auth.decoy_user("nobody").hash, ScramHash::Sha512);
+        assert_eq!(auth.decoy_user("nobody").iterations, 8192);
+    }
+
+    #[test]
+    fn revoke_test() {
+        let users = Arc::new(MemoryUserStore::new(vec![
+            User::new("alice", "pencil", 4096, vec![]).unwrap(),
+            User::new("bob", "pencil", 4096, vec![]).unwrap(),
+        ]));
+
+        let auth = Auth::new(TokenSigner::new(vec![key("a")], "haystack", 3600).unwrap(), users);
+
+        let alice1 = auth.login("alice").unwrap();
+        let alice2 = auth.login("alice").unwrap();
+        let bob = auth.login("bob").unwrap();
+
+        let identity = auth.introspect(&alice1).unwrap();
+        assert!(auth.revoke(&identity.token_id, identity.expires));
+        assert!(!auth.revoke(&identity.token_id, identity.expires));
+        assert!(auth.introspect(&alice1).is_err());
+        assert!(auth.introspect(&alice2).is_ok());
+
+        let revoked = auth.revoke_user("alice");
+        assert!(auth.introspect(&alice2).is_err());
+        assert!(auth.introspect(&bob).is_ok());
+
+        // Logging in again after the revocation gives a valid token
+        let alice3 = auth.login("alice").unwrap();
+        assert!(auth.introspect(&alice3).is_ok());
+
+        // Revocations are forgotten once the tokens they cover have expired
+        assert_eq!(auth.sweep(revoked), 0);
+        assert_eq!(auth.sweep(revoked + 3600), 2);
+        assert!(auth.revoked.lock().is_empty());
+        assert!(auth.revoked_users.lock().is_empty());
+    }
+}
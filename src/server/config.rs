@@ -0,0 +1,354 @@
+//! Server configuration.
+//!
+//! A `ServerConfig` is read from a TOML file, from environment variables or both, with the
+//! environment taking precedence. Settings that are left out keep their defaults.
+//!
+//! ```toml
+//! bind = "0.0.0.0:4337"
+//! tls_cert = "/etc/letsencrypt/live/example.net/cert.pem"
+//! tls_key = "/etc/letsencrypt/live/example.net/privkey.pem"
+//! allowed_origins = ["https://example.net"]
+//! body_limit = 16384
+//! token_lifetime = 3600
+//! token_issuer = "haystack"
+//! users = "/etc/haystack/users.toml"
+//! # For clients that cannot do SCRAM, only over https
+//! plaintext_auth = false
+//! basic_auth = true
+//! # Every user of the users file must have been made with these to log in with SCRAM
+//! scram_hash = "SHA-256"
+//! scram_iterations = 10000
+//!
+//! # The first key signs auth tokens, the others only verify them
+//! [[token_keys]]
+//! id = "2021-02"
+//! secret = "at least 32 bytes of random text......"
+//!
+//! # Operators may write, and users with the siteA role only read siteA
+//! [policy.ops]
+//! hisWrite = ["operator"]
+//! pointWrite = ["operator"]
+//! invokeAction = ["operator"]
+//!
+//! [policy.filters]
+//! siteA = "siteRef==@siteA"
+//!
+//! # Lock out a user or address for 15 minutes after 10 failed logins
+//! [throttle]
+//! lockout_after = 10
+//! lockout = 900
+//! ```
+//!
+//! | variable                    | setting                                   |
+//! |-----------------------------|-------------------------------------------|
+//! | `HAYSTACK_CONFIG`           | TOML file to read before the environment  |
+//! | `HAYSTACK_BIND`             | `bind`                                    |
+//! | `HAYSTACK_TLS_CERT`         | `tls_cert`                                |
+//! | `HAYSTACK_TLS_KEY`          | `tls_key`                                 |
+//! | `HAYSTACK_ALLOWED_ORIGINS`  | `allowed_origins`, separated by commas    |
+//! | `HAYSTACK_BODY_LIMIT`       | `body_limit`                              |
+//! | `HAYSTACK_TOKEN_LIFETIME`   | `token_lifetime`                          |
+//! | `HAYSTACK_TOKEN_ISSUER`     | `token_issuer`                            |
+//! | `HAYSTACK_TOKEN_KEYS`       | `token_keys`, as `id:secret,id:secret`    |
+//! | `HAYSTACK_USERS`            | `users`                                   |
+//! | `HAYSTACK_PLAINTEXT_AUTH`   | `plaintext_auth`, `true` or `false`       |
+//! | `HAYSTACK_BASIC_AUTH`       | `basic_auth`, `true` or `false`           |
+//! | `HAYSTACK_SCRAM_HASH`       | `scram_hash`                              |
+//! | `HAYSTACK_SCRAM_ITERATIONS` | `scram_iterations`                        |
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::error::*;
+
+use super::auth::SigningKey;
+use super::policy::Policy;
+use super::scram::ScramHash;
+use super::throttle::ThrottleConfig;
+use super::user::DEFAULT_ITERATIONS;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The address to listen on.
+    pub bind: SocketAddr,
+    /// PEM certificate chain. The server uses https when both `tls_cert` and `tls_key` are set.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// The origins allowed by CORS, ie `https://example.net`.
+    pub allowed_origins: Vec<String>,
+    /// The largest request body accepted, in bytes.
+    pub body_limit: u64,
+    /// How long an auth token is valid for, in seconds.
+    pub token_lifetime: u64,
+    /// The `iss` of auth tokens. Tokens of other issuers are rejected.
+    pub token_issuer: String,
+    /// The keys auth tokens are signed with. Tokens stay valid across restarts. A random key is
+    /// made when there are none, so tokens are lost when the server restarts.
+    pub token_keys: Vec<SigningKey>,
+    /// The users file of a `FileUserStore`, managed with the `haystack_users` tool. Changes to it
+    /// take effect without restarting the server.
+    pub users: Option<PathBuf>,
+    /// Which roles may call which ops and read which entities, see `Policy`.
+    pub policy: Policy,
+    /// How failed logins are slowed down and locked out, see `Throttle`.
+    pub throttle: ThrottleConfig,
+    /// Accept passwords with the Haystack PLAINTEXT scheme, for clients that cannot do SCRAM.
+    /// Requires TLS.
+    pub plaintext_auth: bool,
+    /// Accept passwords with HTTP Basic auth, for clients that cannot do SCRAM. Requires TLS.
+    pub basic_auth: bool,
+    /// The hash every SCRAM login uses, `SHA-256` or `SHA-512`. Users made with another hash
+    /// cannot log in with SCRAM.
+    pub scram_hash: ScramHash,
+    /// The iteration count every SCRAM login uses. Users made with another count cannot log in
+    /// with SCRAM.
+    pub scram_iterations: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 4337)),
+            tls_cert: None,
+            tls_key: None,
+            allowed_origins: vec!["http://127.0.0.1:4337".to_string(), "http://127.0.0.1:8080".to_string()],
+            body_limit: 1024 * 16,
+            token_lifetime: 60 * 60,
+            token_issuer: "haystack".to_string(),
+            token_keys: vec![],
+            users: None,
+            policy: Policy::default(),
+            throttle: ThrottleConfig::default(),
+            plaintext_auth: false,
+            basic_auth: false,
+            scram_hash: ScramHash::default(),
+            scram_iterations: DEFAULT_ITERATIONS,
+        }
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str, value: &str) -> HaystackResult<T> {
+    value.trim().parse::<T>().map_err(|_| HaystackError::GeneralError(format!("Invalid {}: {}", name, value)))
+}
+
+impl ServerConfig {
+    pub fn from_toml_str(s: &str) -> HaystackResult<Self> {
+        let config = ServerConfig::parse_toml_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> HaystackResult<Self> {
+        ServerConfig::from_toml_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// The config of a toml string, not validated as the environment may still complete it.
+    fn parse_toml_str(s: &str) -> HaystackResult<Self> {
+        toml::from_str(s).map_err(|e| HaystackError::GeneralError(format!("Invalid server config: {}", e)))
+    }
+
+    /// The defaults overridden by the environment.
+    pub fn from_env() -> HaystackResult<Self> {
+        ServerConfig::default().with_env()
+    }
+
+    /// The file named by `HAYSTACK_CONFIG`, or the defaults if it is not set, overridden by the
+    /// environment.
+    pub fn load() -> HaystackResult<Self> {
+        ServerConfig::load_vars(|name| std::env::var(name).ok())
+    }
+
+    /// The config is only validated once the variables are applied, so a file may leave
+    /// settings such as the TLS key to the environment.
+    fn load_vars<F: Fn(&str) -> Option<String>>(var: F) -> HaystackResult<Self> {
+        let config = match var("HAYSTACK_CONFIG") {
+            Some(path) => ServerConfig::parse_toml_str(&std::fs::read_to_string(path)?)?,
+            None => ServerConfig::default(),
+        };
+
+        config.with_vars(var)
+    }
+
+    /// This config with the settings that are set in the environment replaced.
+    pub fn with_env(self) -> HaystackResult<Self> {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    fn with_vars<F: Fn(&str) -> Option<String>>(mut self, var: F) -> HaystackResult<Self> {
+        if let Some(bind) = var("HAYSTACK_BIND") {
+            self.bind = parse_var("HAYSTACK_BIND", &bind)?;
+        }
+
+        if let Some(cert) = var("HAYSTACK_TLS_CERT") {
+            self.tls_cert = Some(PathBuf::from(cert));
+        }
+
+        if let Some(key) = var("HAYSTACK_TLS_KEY") {
+            self.tls_key = Some(PathBuf::from(key));
+        }
+
+        if let Some(origins) = var("HAYSTACK_ALLOWED_ORIGINS") {
+            self.allowed_origins = origins.split(',').map(|o| o.trim().to_string()).filter(|o| !o.is_empty()).collect();
+        }
+
+        if let Some(limit) = var("HAYSTACK_BODY_LIMIT") {
+            self.body_limit = parse_var("HAYSTACK_BODY_LIMIT", &limit)?;
+        }
+
+        if let Some(lifetime) = var("HAYSTACK_TOKEN_LIFETIME") {
+            self.token_lifetime = parse_var("HAYSTACK_TOKEN_LIFETIME", &lifetime)?;
+        }
+
+        if let Some(issuer) = var("HAYSTACK_TOKEN_ISSUER") {
+            self.token_issuer = issuer;
+        }
+
+        if let Some(keys) = var("HAYSTACK_TOKEN_KEYS") {
+            self.token_keys = keys.split(',').filter(|k| !k.trim().is_empty()).map(|k| match k.split_once(':') {
+                Some((id, secret)) => Ok(SigningKey { id: id.trim().to_string(), secret: secret.trim().to_string() }),
+                None => Err(HaystackError::GeneralError("Invalid HAYSTACK_TOKEN_KEYS, expected id:secret".to_string())),
+            }).collect::<HaystackResult<Vec<SigningKey>>>()?;
+        }
+
+        if let Some(users) = var("HAYSTACK_USERS") {
+            self.users = Some(PathBuf::from(users));
+        }
+
+        if let Some(plaintext) = var("HAYSTACK_PLAINTEXT_AUTH") {
+            self.plaintext_auth = parse_var("HAYSTACK_PLAINTEXT_AUTH", &plaintext)?;
+        }
+
+        if let Some(basic) = var("HAYSTACK_BASIC_AUTH") {
+            self.basic_auth = parse_var("HAYSTACK_BASIC_AUTH", &basic)?;
+        }
+
+        if let Some(hash) = var("HAYSTACK_SCRAM_HASH") {
+            self.scram_hash = ScramHash::from_name(&hash).ok_or_else(|| HaystackError::GeneralError(format!("Invalid HAYSTACK_SCRAM_HASH: {}", hash)))?;
+        }
+
+        if let Some(iterations) = var("HAYSTACK_SCRAM_ITERATIONS") {
+            self.scram_iterations = parse_var("HAYSTACK_SCRAM_ITERATIONS", &iterations)?;
+        }
+
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Checks the settings warp would otherwise panic on when the server starts.
+    pub fn validate(&self) -> HaystackResult<()> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(HaystackError::GeneralError("tls_cert and tls_key must be set together".to_string()));
+        }
+
+        if let Some(origin) = self.allowed_origins.iter().find(|o| {
+            let host = o.strip_prefix("http://").or_else(|| o.strip_prefix("https://"));
+            host.is_none_or(|host| host.is_empty() || host.contains('/'))
+        }) {
+            return Err(HaystackError::GeneralError(format!("Invalid allowed origin {}", origin)));
+        }
+
+        // Both send the password itself
+        if (self.plaintext_auth || self.basic_auth) && self.tls().is_none() {
+            return Err(HaystackError::GeneralError("plaintext_auth and basic_auth require tls_cert and tls_key".to_string()));
+        }
+
+        if self.scram_iterations == 0 {
+            return Err(HaystackError::GeneralError("scram_iterations must be at least 1".to_string()));
+        }
+
+        if self.token_lifetime == 0 {
+            return Err(HaystackError::GeneralError("token_lifetime must be at least one second".to_string()));
+        }
+
+        if let Some(key) = self.token_keys.iter().find(|k| k.secret.len() < SigningKey::MIN_SECRET_LEN) {
+            return Err(HaystackError::GeneralError(format!("Token key {} is shorter than {} bytes", key.id, SigningKey::MIN_SECRET_LEN)));
+        }
+
+        self.policy.validate()
+    }
+
+    /// The TLS certificate and key paths, if the server should use https.
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn config_test() {
+        let config = ServerConfig::from_toml_str("bind = \"127.0.0.1:8000\"\nallowed_origins = [\"https://example.net\"]\ntoken_lifetime = 600\n").unwrap();
+        assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 8000)));
+        assert_eq!(config.allowed_origins, vec!["https://example.net".to_string()]);
+        assert_eq!(config.token_lifetime, 600);
+        assert_eq!(config.body_limit, ServerConfig::default().body_limit);
+        assert_eq!(config.tls(), None);
+
+        assert!(ServerConfig::from_toml_str("port = 80").is_err());
+        assert!(ServerConfig::from_toml_str("tls_cert = \"cert.pem\"").is_err());
+        assert!(ServerConfig::from_toml_str("allowed_origins = [\"example.net\"]").is_err());
+
+        let vars: HashMap<&str, &str> = vec![
+            ("HAYSTACK_BIND", "0.0.0.0:443"),
+            ("HAYSTACK_TLS_CERT", "cert.pem"),
+            ("HAYSTACK_TLS_KEY", "key.pem"),
+            ("HAYSTACK_ALLOWED_ORIGINS", "https://a.example.net, https://b.example.net"),
+            ("HAYSTACK_USERS", "users.toml"),
+            ("HAYSTACK_TOKEN_KEYS", "new:0123456789abcdef0123456789abcdef, old:fedcba9876543210fedcba9876543210"),
+        ].into_iter().collect();
+
+        let config = config.with_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.bind.port(), 443);
+        assert_eq!(config.tls(), Some((Path::new("cert.pem"), Path::new("key.pem"))));
+        assert_eq!(config.allowed_origins.len(), 2);
+        assert_eq!(config.token_lifetime, 600);
+        assert_eq!(config.users, Some(PathBuf::from("users.toml")));
+        assert_eq!(config.token_keys.iter().map(|k| k.id.as_str()).collect::<Vec<&str>>(), vec!["new", "old"]);
+        assert_eq!(config.token_keys[1].secret, "fedcba9876543210fedcba9876543210");
+
+        assert!(ServerConfig::from_toml_str("basic_auth = true").is_err());
+        let config = config.with_vars(|name| if name == "HAYSTACK_BASIC_AUTH" { Some("true".to_string()) } else { None }).unwrap();
+        assert!(config.basic_auth && !config.plaintext_auth);
+
+        let config = ServerConfig::from_toml_str("[policy.filters]\nsiteA = \"siteRef==@siteA\"\n").unwrap();
+        assert_eq!(config.policy.ops, Policy::default().ops);
+        assert_eq!(config.policy.filters.get("siteA").map(|f| f.as_str()), Some("siteRef==@siteA"));
+        assert!(ServerConfig::from_toml_str("[policy.filters]\nsiteA = \"siteRef==\"\n").is_err());
+
+        assert!(ServerConfig::from_toml_str("[[token_keys]]\nid = \"a\"\nsecret = \"short\"\n").is_err());
+
+        let config = ServerConfig::from_toml_str("scram_hash = \"SHA-512\"\nscram_iterations = 20000\n").unwrap();
+        assert_eq!((config.scram_hash, config.scram_iterations), (ScramHash::Sha512, 20000));
+        assert!(ServerConfig::from_toml_str("scram_iterations = 0").is_err());
+        assert!(ServerConfig::default().with_vars(|name| if name == "HAYSTACK_SCRAM_HASH" { Some("MD5".to_string()) } else { None }).is_err());
+
+        assert!(ServerConfig::default().with_vars(|name| if name == "HAYSTACK_BODY_LIMIT" { Some("lots".to_string()) } else { None }).is_err());
+    }
+
+    #[test]
+    fn load_test() {
+        let path = std::env::temp_dir().join(format!("haystack-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "tls_cert = \"cert.pem\"\n").unwrap();
+
+        // The file is only valid with the key from the environment
+        let mut vars: HashMap<&str, String> = vec![("HAYSTACK_CONFIG", path.to_string_lossy().to_string())].into_iter().collect();
+        assert!(ServerConfig::from_file(&path).is_err());
+        assert!(ServerConfig::load_vars(|name| vars.get(name).cloned()).is_err());
+
+        vars.insert("HAYSTACK_TLS_KEY", "key.pem".to_string());
+        let config = ServerConfig::load_vars(|name| vars.get(name).cloned()).unwrap();
+        assert_eq!(config.tls(), Some((Path::new("cert.pem"), Path::new("key.pem"))));
+
+        std::fs::write(&path, "port = 80\n").unwrap();
+        assert!(ServerConfig::load_vars(|name| vars.get(name).cloned()).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
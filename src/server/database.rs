@@ -0,0 +1,126 @@
+//! Storage backend for the haystack server.
+//!
+//! The warp routes handle auth, parsing and encoding, and ask a `HaystackDatabase` for the data.
+//! Implement the trait to serve your own entities and history. `MemoryDatabase` is a simple
+//! implementation that keeps everything in memory.
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+
+use crate::error::*;
+use crate::filter::{ref_id, RefTag, RefTags};
+use crate::token::*;
+
+use super::point::PriorityArray;
+
+/// A history sample, timestamp and value.
+pub type HisItem = (DateTime<FixedOffset>, Token);
+
+fn not_supported<T>(op: &str) -> HaystackResult<T> {
+    Err(HaystackError::NotSupported(op.to_string()))
+}
+
+/// The entities that entities of `db` matching `filter` point at through their `ref_tag` refs,
+/// found with `read_by_filter` and `read_by_ids`.
+pub async fn referenced_by<D: HaystackDatabase + ?Sized>(db: &D, filter: &str, ref_tag: &str) -> HaystackResult<RefTags> {
+    let mut ids: Vec<Token> = vec![];
+
+    for (_, tags) in db.read_by_filter(filter, None).await? {
+        for tag in tags.iter().filter(|t| t.get_id() == ref_tag) {
+            match tag.get_value::<Token>() {
+                Some(target) if ref_id(&target).is_some() && !ids.iter().any(|id| ref_id(id) == ref_id(&target)) => ids.push(target),
+                _ => {},
+            }
+        }
+    }
+
+    Ok(db.read_by_ids(&ids).await?.into_iter().flatten().collect())
+}
+
+#[async_trait]
+pub trait HaystackDatabase: Send + Sync {
+
+    /// Returns the entity for each id in the same order, or `None` where no entity has that id.
+    async fn read_by_ids(&self, ids: &[Token]) -> HaystackResult<Vec<Option<RefTag>>>;
+
+    /// Returns the entities matching a haystack filter, at most `limit` of them.
+    async fn read_by_filter(&self, filter: &str, limit: Option<usize>) -> HaystackResult<RefTags>;
+
+    /// Returns the entities whose `ref_tag` ref points at `target`, ie the points of an equip via
+    /// `equipRef`.
+    async fn read_referrers(&self, target: &Token, ref_tag: &str) -> HaystackResult<RefTags> {
+        let candidates = self.read_by_filter(ref_tag, None).await?;
+        let target = ref_id(target);
+
+        Ok(candidates.into_iter().filter(|(_, tags)| {
+            tags.iter().any(|t| t.get_id() == ref_tag && t.get_value::<Token>().as_ref().and_then(ref_id) == target)
+        }).collect())
+    }
+
+    /// Returns the entities that entities matching `filter` point at through their `ref_tag` refs,
+    /// ie the equips with an alarm for `("point and alarm", "equipRef")`.
+    async fn read_referenced_by(&self, filter: &str, ref_tag: &str) -> HaystackResult<RefTags> {
+        referenced_by(self, filter, ref_tag).await
+    }
+
+    /// Returns the children of `nav_id`, or the roots if it is `None`.
+    ///
+    /// Returning `None` lets the server build the navigation tree itself.
+    async fn nav(&self, _nav_id: Option<&Token>) -> HaystackResult<Option<Grid>> {
+        Ok(None)
+    }
+
+    /// Samples of a historized point with `start <= ts < end`, ordered by timestamp.
+    async fn his_read(&self, _id: &Token, _start: DateTime<FixedOffset>, _end: DateTime<FixedOffset>) -> HaystackResult<Vec<HisItem>> {
+        not_supported("hisRead")
+    }
+
+    /// Stores samples of a historized point. Samples may be out of order, and a sample with the
+    /// timestamp of a stored sample replaces it.
+    async fn his_write(&self, _id: &Token, _items: Vec<HisItem>) -> HaystackResult<()> {
+        not_supported("hisWrite")
+    }
+
+    /// Samples of several historized points, a list per id in the order of `ids`.
+    async fn his_read_many(&self, ids: &[Token], start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> HaystackResult<Vec<Vec<HisItem>>> {
+        let mut series = Vec::with_capacity(ids.len());
+
+        for id in ids {
+            series.push(self.his_read(id, start, end).await?);
+        }
+
+        Ok(series)
+    }
+
+    /// Stores samples of several historized points, as `his_write` does for each.
+    async fn his_write_many(&self, writes: Vec<(Token, Vec<HisItem>)>) -> HaystackResult<()> {
+        for (id, items) in writes {
+            self.his_write(&id, items).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `val` at priority `level` (1 to 16) of a writable point. A `None` val releases the level.
+    async fn point_write(&self, _id: &Token, _level: u8, _val: Option<Token>, _who: &str, _duration: Option<Token>) -> HaystackResult<()> {
+        not_supported("pointWrite")
+    }
+
+    /// The priority array of a writable point.
+    async fn point_write_array(&self, _id: &Token) -> HaystackResult<PriorityArray> {
+        not_supported("pointWrite")
+    }
+
+    /// Called when ids are added to a watch, so the backend can start collecting live values.
+    async fn watch_sub(&self, _ids: &[Token]) -> HaystackResult<()> {
+        Ok(())
+    }
+
+    /// Called when ids are no longer in any watch.
+    async fn watch_unsub(&self, _ids: &[Token]) -> HaystackResult<()> {
+        Ok(())
+    }
+
+    async fn invoke_action(&self, _id: &Token, _action: &str, _args: Vec<Tag>) -> HaystackResult<Grid> {
+        not_supported("invokeAction")
+    }
+}
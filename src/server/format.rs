@@ -0,0 +1,407 @@
+//! Grid formats and content negotiation.
+//!
+//! Request grids are decoded with the format named by the `Content-Type` header and responses
+//! are encoded with the best format allowed by the `Accept` header. Both default to zinc when
+//! the header is missing. The formats op lists the registered formats.
+//!
+//! | mime                          | receive | send |
+//! |-------------------------------|---------|------|
+//! | `text/zinc`                   | M       | M    |
+//! | `text/plain`                  | M       | M    |
+//! | `application/json`            | M       | M    |
+//! | `application/vnd.hayson+json` | M       | M    |
+//! | `text/csv`                    |         | M    |
+//!
+//! `application/json` is the Haystack 3 JSON encoding and `application/vnd.hayson+json` the
+//! Haystack 4 one, see `crate::json`.
+//!
+//! A GET request sends a single row request grid as its query string instead, with a
+//! column per parameter, ie `/hisRead?id=@x&range=today`.
+use std::str;
+use std::sync::Arc;
+
+use warp::{reject, Filter, Rejection, Reply};
+
+use crate::error::*;
+use crate::hval::HVal;
+use crate::json;
+use crate::token::*;
+use crate::zinc_tokenizer::{grid, token};
+
+/// A grid format. Formats without `encode` can only be received and formats without `decode`
+/// can only be sent.
+#[derive(Clone, Copy)]
+pub struct Format {
+    pub mime: &'static str,
+    pub encode: Option<fn(&Grid) -> String>,
+    pub decode: Option<fn(&str) -> HaystackResult<Grid>>,
+}
+
+impl Format {
+    /// A response with `grid` encoded in this format.
+    pub fn reply(&self, grid: &Grid, status: http::StatusCode) -> warp::reply::Response {
+        let body = self.encode.map(|encode| encode(grid)).unwrap_or_default();
+        let reply = warp::reply::with_header(body, "Content-Type", format!("{}; charset=utf-8", self.mime));
+
+        warp::reply::with_status(reply, status).into_response()
+    }
+
+    /// A response with an empty body.
+    pub fn empty(&self, status: http::StatusCode) -> warp::reply::Response {
+        warp::reply::with_status(String::new(), status).into_response()
+    }
+
+    /// Decodes a request body, `None` if it is empty.
+    pub fn decode_bytes(&self, bytes: &[u8]) -> HaystackResult<Option<Grid>> {
+        let s = str::from_utf8(bytes).map_err(|e| HaystackError::GeneralError(format!("Invalid {} request: {}", self.mime, e)))?;
+
+        if s.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let decode = self.decode.ok_or_else(|| HaystackError::NotSupported(format!("Decoding {}", self.mime)))?;
+
+        match decode(s) {
+            Ok(grid) => Ok(Some(grid)),
+            Err(e) => {
+                debug!("Invalid {} request: {}", self.mime, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Format({})", self.mime)
+    }
+}
+
+fn encode_zinc(grid: &Grid) -> String {
+    grid.to_zinc()
+}
+
+fn decode_zinc(s: &str) -> HaystackResult<Grid> {
+    grid(s).map(|(_, g)| g).map_err(|e| HaystackError::GeneralError(format!("Invalid zinc grid: {:?}", e)))
+}
+
+fn encode_json(grid: &Grid) -> String {
+    json::to_json(grid).to_string()
+}
+
+fn encode_hayson(grid: &Grid) -> String {
+    json::to_hayson(grid).to_string()
+}
+
+fn csv_cell(val: &Val) -> String {
+    let s = match val.cast_to_type_ref::<Token>() {
+        Some(Token::Empty) | Some(Token::Null) => String::new(),
+        Some(Token::Marker) => "\u{2713}".to_string(),
+        Some(token) => token.to_string(),
+        None => val.to_zinc(),
+    };
+
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    }
+    else {
+        s
+    }
+}
+
+/// Encodes a grid as CSV with a header of column names. The grid and column meta are dropped.
+fn encode_csv(grid: &Grid) -> String {
+    let mut lines = vec![(0..grid.cols.len())
+        .map(|i| csv_cell(&Val::new(Box::new(Token::EscapedString(grid.cols[i].get_id_as_str().unwrap_or_default())))))
+        .collect::<Vec<String>>()
+        .join(",")];
+
+    for r in 0..grid.rows.len() {
+        let row = &grid.rows[r];
+        lines.push((0..row.len()).map(|i| csv_cell(&row[i])).collect::<Vec<String>>().join(","));
+    }
+
+    lines.join("\n")
+}
+
+/// The formats of a server, in order of preference.
+#[derive(Debug, Clone)]
+pub struct Formats {
+    formats: Vec<Format>,
+}
+
+impl Default for Formats {
+    fn default() -> Self {
+        Formats {
+            formats: vec![
+                Format { mime: "text/zinc", encode: Some(encode_zinc), decode: Some(decode_zinc) },
+                Format { mime: "text/plain", encode: Some(encode_zinc), decode: Some(decode_zinc) },
+                Format { mime: "application/json", encode: Some(encode_json), decode: Some(json::grid_from_json) },
+                Format { mime: "application/vnd.hayson+json", encode: Some(encode_hayson), decode: Some(json::grid_from_hayson) },
+                Format { mime: "text/csv", encode: Some(encode_csv), decode: None },
+            ],
+        }
+    }
+}
+
+/// The mime type of a header value without parameters, ie `text/zinc` for `text/zinc; charset=utf-8`.
+fn essence(media_type: &str) -> String {
+    media_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+impl Formats {
+    /// The default formats, zinc, JSON, Hayson and CSV.
+    pub fn new() -> Self {
+        Formats::default()
+    }
+
+    /// Adds `format`, replacing any format with the same mime type.
+    pub fn register(&mut self, format: Format) {
+        match self.formats.iter_mut().find(|f| f.mime == format.mime) {
+            Some(existing) => *existing = format,
+            None => self.formats.push(format),
+        }
+    }
+
+    /// The format to encode a response with for an `Accept` header, `None` if no format is
+    /// acceptable.
+    pub fn encoder(&self, accept: Option<&str>) -> Option<Format> {
+        let encoders = || self.formats.iter().filter(|f| f.encode.is_some());
+
+        let accept = match accept {
+            Some(accept) if !accept.trim().is_empty() => accept,
+            _ => return encoders().next().copied(),
+        };
+
+        // (q, media range), highest q first and in header order for the same q
+        let mut ranges: Vec<(f32, String)> = accept.split(',').map(|range| {
+            let q = range.split(';').skip(1)
+                .find_map(|param| param.trim().strip_prefix("q=").map(|q| q.trim().parse::<f32>().unwrap_or(0.0)))
+                .unwrap_or(1.0);
+
+            (q, essence(range))
+        }).collect();
+
+        ranges.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        ranges.iter().filter(|(q, _)| *q > 0.0).find_map(|(_, range)| {
+            encoders().find(|f| match range.strip_suffix("/*") {
+                Some("*") => true,
+                Some(kind) => f.mime.split('/').next() == Some(kind),
+                None => f.mime == range,
+            }).copied()
+        })
+    }
+
+    /// The format to encode an error response with for an `Accept` header, zinc if no format is
+    /// acceptable.
+    pub fn error_encoder(&self, accept: Option<&str>) -> Format {
+        self.encoder(accept)
+            .or_else(|| self.encoder(Some("text/zinc")))
+            .unwrap_or(Format { mime: "text/zinc", encode: Some(encode_zinc), decode: Some(decode_zinc) })
+    }
+
+    /// The format to decode a request with for a `Content-Type` header, `None` if the type is
+    /// not supported.
+    pub fn decoder(&self, content_type: Option<&str>) -> Option<Format> {
+        let decoders = || self.formats.iter().filter(|f| f.decode.is_some());
+
+        match content_type {
+            Some(content_type) => {
+                let mime = essence(content_type);
+                decoders().find(|f| f.mime == mime).copied()
+            },
+            None => decoders().next().copied(),
+        }
+    }
+
+    /// The response of the formats op.
+    pub fn to_grid(&self) -> Grid {
+        let cols = Cols::new(["mime", "receive", "send"].iter()
+            .map(|name| Col::new(Token::Id(name.to_string()), None))
+            .collect());
+
+        let marker = |b: bool| if b { Token::Marker } else { Token::Empty };
+
+        let rows = self.formats.iter().map(|f| {
+            Row::new(vec![Token::EscapedString(f.mime.to_string()), marker(f.decode.is_some()), marker(f.encode.is_some())]
+                .into_iter().map(|t| Val::new(Box::new(t))).collect())
+        }).collect();
+
+        Grid::new(GridMeta::new(Token::Ver("3.0".into()), None), cols, Rows::new(rows))
+    }
+}
+
+/// No format matches the `Accept` header.
+#[derive(Debug)]
+pub struct NotAcceptable;
+
+impl reject::Reject for NotAcceptable {}
+
+/// No format matches the `Content-Type` header.
+#[derive(Debug)]
+pub struct UnsupportedMediaType;
+
+impl reject::Reject for UnsupportedMediaType {}
+
+/// The body is not a valid grid in the format of the `Content-Type` header.
+#[derive(Debug)]
+pub struct InvalidRequestGrid(pub String);
+
+impl reject::Reject for InvalidRequestGrid {}
+
+/// Extracts the format to encode the response with from the `Accept` header.
+pub fn response_format(formats: Arc<Formats>) -> impl Filter<Extract = (Format,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept").and_then(move |accept: Option<String>| {
+        let format = formats.encoder(accept.as_deref());
+
+        async move {
+            format.ok_or_else(|| reject::custom(NotAcceptable))
+        }
+    })
+}
+
+/// Extracts the request grid from the body, decoded with the format of the `Content-Type`
+/// header. The grid is `None` if the body is empty, and bodies that are not valid in that
+/// format are rejected.
+pub fn request_grid(formats: Arc<Formats>) -> impl Filter<Extract = (Option<Grid>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::bytes())
+        .and_then(move |content_type: Option<String>, body: warp::hyper::body::Bytes| {
+            let format = formats.decoder(content_type.as_deref());
+
+            async move {
+                match format {
+                    Some(format) => format.decode_bytes(&body).map_err(|e| reject::custom(InvalidRequestGrid(e.to_string()))),
+                    None => Err(reject::custom(UnsupportedMediaType)),
+                }
+            }
+        })
+}
+
+/// The single row request grid of query string parameters, `None` if there are none. Each value
+/// is parsed as a zinc scalar, so `@x` is a Ref and `10` a Number, and values that are not, ie
+/// `point and his`, are strings. A repeated parameter keeps its first value.
+pub fn grid_from_query(params: &[(String, String)]) -> Option<Grid> {
+    let mut names: Vec<&str> = vec![];
+    let mut vals: Vec<Val> = vec![];
+
+    for (name, value) in params {
+        if names.contains(&name.as_str()) {
+            continue;
+        }
+
+        let value = match token(value) {
+            Ok(("", t)) => t,
+            _ => Token::EscapedString(value.to_string()),
+        };
+
+        names.push(name);
+        vals.push(Val::new(Box::new(value)));
+    }
+
+    if names.is_empty() {
+        return None;
+    }
+
+    let cols = Cols::new(names.iter().map(|name| Col::new(Token::Id(name.to_string()), None)).collect());
+
+    Some(Grid::new(GridMeta::new(Token::Ver("3.0".into()), None), cols, Rows::new(vec![Row::new(vals)])))
+}
+
+/// Extracts the request grid from the query string, see `grid_from_query`.
+pub fn query_grid() -> impl Filter<Extract = (Option<Grid>,), Error = Rejection> + Clone {
+    warp::query::<Vec<(String, String)>>().map(|params: Vec<(String, String)>| grid_from_query(&params))
+}
+
+/// Extracts the request grid of an op, from the query string of a GET or the body of a POST.
+pub fn request(formats: Arc<Formats>, body_limit: u64) -> impl Filter<Extract = (Option<Grid>,), Error = Rejection> + Clone {
+    let get = warp::get().and(query_grid());
+
+    let post = warp::post()
+        .and(warp::body::content_length_limit(body_limit))
+        .and(request_grid(formats));
+
+    get.or(post).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_test() {
+        let formats = Formats::new();
+        let mime = |f: Option<Format>| f.map(|f| f.mime);
+
+        assert_eq!(mime(formats.encoder(None)), Some("text/zinc"));
+        assert_eq!(mime(formats.encoder(Some("*/*"))), Some("text/zinc"));
+        assert_eq!(mime(formats.encoder(Some("application/json"))), Some("application/json"));
+        assert_eq!(mime(formats.encoder(Some("text/zinc;q=0.5, application/vnd.hayson+json"))), Some("application/vnd.hayson+json"));
+        assert_eq!(mime(formats.encoder(Some("text/csv;q=0, text/*"))), Some("text/zinc"));
+        assert_eq!(mime(formats.encoder(Some("image/png"))), None);
+
+        assert_eq!(mime(formats.decoder(None)), Some("text/zinc"));
+        assert_eq!(mime(formats.decoder(Some("application/json; charset=utf-8"))), Some("application/json"));
+        assert_eq!(mime(formats.decoder(Some("text/csv"))), None);
+
+        let body = br#"{"meta": {"ver": "3.0"}, "cols": [{"name": "filter"}], "rows": [{"filter": "site"}]}"#;
+        let request = formats.decoder(Some("application/json")).unwrap().decode_bytes(body).unwrap().unwrap();
+        assert_eq!(request.to_zinc(), "ver:\"3.0\"\nfilter\n\"site\"");
+        assert!(formats.decoder(None).unwrap().decode_bytes(b"{}").is_err());
+        assert!(formats.decoder(None).unwrap().decode_bytes(b"").unwrap().is_none());
+
+        assert_eq!(formats.to_grid().rows.len(), 5);
+    }
+
+    #[test]
+    fn filters_test() {
+        use futures::executor::block_on;
+
+        let formats = Arc::new(Formats::new());
+        let filter = response_format(formats.clone()).and(request_grid(formats));
+
+        let (format, request) = block_on(warp::test::request().method("POST")
+            .header("accept", "application/json")
+            .header("content-type", "text/zinc")
+            .body("ver:\"3.0\"\nfilter\n\"site\"")
+            .filter(&filter)).unwrap();
+
+        assert_eq!(format.mime, "application/json");
+        assert!(request.is_some());
+
+        let rejection = block_on(warp::test::request().method("POST").header("accept", "image/png").body("").filter(&filter)).unwrap_err();
+        assert!(rejection.find::<NotAcceptable>().is_some());
+
+        let rejection = block_on(warp::test::request().method("POST").header("content-type", "text/csv").body("a").filter(&filter)).unwrap_err();
+        assert!(rejection.find::<UnsupportedMediaType>().is_some());
+    }
+
+    #[test]
+    fn query_grid_test() {
+        use futures::executor::block_on;
+
+        let params = |q: &[(&str, &str)]| q.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect::<Vec<(String, String)>>();
+
+        let g = grid_from_query(&params(&[("id", "@x"), ("range", "today"), ("limit", "10"), ("filter", "siteRef==@a and point"), ("id", "@y")])).unwrap();
+        assert_eq!(g.to_zinc(), "ver:\"3.0\"\nid,range,limit,filter\n@x,\"today\",10,\"siteRef==@a and point\"");
+        assert!(grid_from_query(&[]).is_none());
+
+        let filter = request(Arc::new(Formats::new()), 1024);
+
+        let get = block_on(warp::test::request().method("GET").path("/hisRead?id=@x&range=%222012-10-01%22").filter(&filter)).unwrap().unwrap();
+        assert_eq!(get.to_zinc(), "ver:\"3.0\"\nid,range\n@x,\"2012-10-01\"");
+
+        let post = block_on(warp::test::request().method("POST").path("/hisRead?id=@y").body("ver:\"3.0\"\nid\n@x").filter(&filter)).unwrap().unwrap();
+        assert_eq!(post.to_zinc(), "ver:\"3.0\"\nid\n@x");
+
+        assert!(block_on(warp::test::request().method("GET").path("/read").filter(&filter)).unwrap().is_none());
+        assert!(block_on(warp::test::request().method("PUT").path("/read").filter(&filter)).is_err());
+    }
+
+    #[test]
+    fn csv_test() {
+        let (_, g) = grid("ver:\"3.0\"\nid,dis,site\n@a,\"Site, A\",M\n@b,\"B\",\n").unwrap();
+        assert_eq!(encode_csv(&g), "id,dis,site\n@a,\"Site, A\",\u{2713}\n@b,B,");
+    }
+}
@@ -0,0 +1,272 @@
+//! In-memory `HaystackDatabase`, useful for tests and small deployments.
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use parking_lot::Mutex;
+
+use crate::error::*;
+use crate::filter::{filter_eval_str, filter_eval_str_referenced_by_with_index, RefTag, RefTags, ReverseRefIndex};
+use crate::token::*;
+
+use super::database::{HaystackDatabase, HisItem};
+use super::point::{apply_priority_array, now, PriorityArray};
+
+/// The key of an entity, its ref id without the display string or leading `@`.
+fn ref_id(token: &Token) -> String {
+    match crate::filter::ref_id(token) {
+        Some(id) => id.to_string(),
+        None => token.to_string(),
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryDatabase {
+    // Val is not Sync so a Mutex rather than a RwLock
+    entities: Mutex<RefTags>,
+    // which entities reference each entity, updated with the entities
+    refs: Mutex<ReverseRefIndex>,
+    // ref id -> samples ordered by timestamp
+    history: Mutex<HashMap<String, Vec<HisItem>>>,
+    // ref id -> priority array of writable points
+    arrays: Mutex<HashMap<String, PriorityArray>>,
+}
+
+impl MemoryDatabase {
+    pub fn new(entities: RefTags) -> Self {
+        MemoryDatabase {
+            refs: Mutex::new(ReverseRefIndex::new(&entities)),
+            entities: Mutex::new(entities),
+            history: Mutex::new(HashMap::new()),
+            arrays: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds an entity, replacing any existing entity with the same id.
+    pub fn insert(&self, entity: RefTag) {
+        let mut entities = self.entities.lock();
+        let id = ref_id(&entity.0);
+
+        self.refs.lock().insert(&entity);
+
+        match entities.iter().position(|e| ref_id(&e.0) == id) {
+            Some(i) => entities[i] = entity,
+            None => entities.push(entity),
+        }
+    }
+
+    pub fn remove(&self, id: &Token) -> Option<RefTag> {
+        let mut entities = self.entities.lock();
+        let id = ref_id(id);
+        let i = entities.iter().position(|e| ref_id(&e.0) == id)?;
+        let entity = entities.remove(i);
+
+        self.refs.lock().remove(&entity.0);
+
+        Some(entity)
+    }
+
+    /// Reapplies the priority arrays with timed levels, so a point reads the level below once a
+    /// timed level expires.
+    fn expire_writes(&self, entities: &mut RefTags) {
+        let arrays = self.arrays.lock();
+        let now = now();
+
+        for (key, array) in arrays.iter().filter(|(_, array)| array.has_timed_levels()) {
+            if let Some(entity) = entities.iter_mut().find(|e| ref_id(&e.0) == *key) {
+                apply_priority_array(&mut entity.1, array, now);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.lock().is_empty()
+    }
+}
+
+#[async_trait]
+impl HaystackDatabase for MemoryDatabase {
+
+    async fn read_by_ids(&self, ids: &[Token]) -> HaystackResult<Vec<Option<RefTag>>> {
+        let mut entities = self.entities.lock();
+        self.expire_writes(&mut entities);
+
+        Ok(ids.iter().map(|id| {
+            let id = ref_id(id);
+            entities.iter().find(|e| ref_id(&e.0) == id).cloned()
+        }).collect())
+    }
+
+    async fn read_by_filter(&self, filter: &str, limit: Option<usize>) -> HaystackResult<RefTags> {
+        let mut entities = self.entities.lock();
+        self.expire_writes(&mut entities);
+        let mut found = filter_eval_str(filter, &entities)?;
+
+        if let Some(limit) = limit {
+            found.truncate(limit);
+        }
+
+        Ok(found)
+    }
+
+    async fn read_referrers(&self, target: &Token, ref_tag: &str) -> HaystackResult<RefTags> {
+        let referrers = self.refs.lock().referrers_via(target, ref_tag);
+        Ok(self.read_by_ids(&referrers).await?.into_iter().flatten().collect())
+    }
+
+    async fn read_referenced_by(&self, filter: &str, ref_tag: &str) -> HaystackResult<RefTags> {
+        let mut entities = self.entities.lock();
+        self.expire_writes(&mut entities);
+        Ok(filter_eval_str_referenced_by_with_index(filter, ref_tag, &entities, &self.refs.lock())?)
+    }
+
+    async fn his_read(&self, id: &Token, start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> HaystackResult<Vec<HisItem>> {
+        let history = self.history.lock();
+
+        Ok(history.get(&ref_id(id)).map(|items| {
+            items.iter().filter(|(ts, _)| *ts >= start && *ts < end).cloned().collect()
+        }).unwrap_or_default())
+    }
+
+    async fn his_write(&self, id: &Token, items: Vec<HisItem>) -> HaystackResult<()> {
+        let mut history = self.history.lock();
+        let samples = history.entry(ref_id(id)).or_default();
+
+        // Keep samples ordered, a sample at an existing timestamp replaces it
+        for (ts, val) in items {
+            match samples.binary_search_by_key(&ts, |item| item.0) {
+                Ok(i) => samples[i].1 = val,
+                Err(i) => samples.insert(i, (ts, val)),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn point_write(&self, id: &Token, level: u8, val: Option<Token>, who: &str, duration: Option<Token>) -> HaystackResult<()> {
+        let mut entities = self.entities.lock();
+        let key = ref_id(id);
+
+        let tags = match entities.iter_mut().find(|e| ref_id(&e.0) == key) {
+            Some(entity) if entity.1.contains(&Tag::new_marker("writable")) => &mut entity.1,
+            Some(_) => return Err(HaystackError::GeneralError(format!("{} is not writable", key))),
+            None => return Err(HaystackError::UnknownRecord(key)),
+        };
+
+        let mut arrays = self.arrays.lock();
+        let array = arrays.entry(key).or_default();
+        let now = now();
+
+        array.write(level, val, who, duration, now)?;
+        apply_priority_array(tags, array, now);
+
+        Ok(())
+    }
+
+    async fn point_write_array(&self, id: &Token) -> HaystackResult<PriorityArray> {
+        let entities = self.entities.lock();
+        let key = ref_id(id);
+
+        match entities.iter().find(|e| ref_id(&e.0) == key) {
+            Some(entity) if entity.1.contains(&Tag::new_marker("writable")) =>
+                Ok(self.arrays.lock().get(&key).cloned().unwrap_or_default()),
+            Some(_) => Err(HaystackError::GeneralError(format!("{} is not writable", key))),
+            None => Err(HaystackError::UnknownRecord(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+
+    fn ts(hour: u32) -> DateTime<FixedOffset> {
+        DateTime::<FixedOffset>::from(Utc.ymd(2020, 1, 1).and_hms(hour, 0, 0))
+    }
+
+    #[test]
+    fn memory_database_test() {
+        let db = MemoryDatabase::new(vec![
+            (Token::Ref("@site".to_string(), None), vec![Tag::new_marker("site")]),
+            (Token::Ref("@temp".to_string(), None), vec![Tag::new_marker("point"), Tag::new_marker("his"), Tag::new_ref("siteRef", "@site")]),
+        ]);
+
+        let found = block_on(db.read_by_ids(&[Token::Ref("@temp".to_string(), Some("Temp".to_string())), Token::Ref("@missing".to_string(), None)])).unwrap();
+        assert_eq!(found[0].as_ref().map(|e| e.0.clone()), Some(Token::Ref("@temp".to_string(), None)));
+        assert!(found[1].is_none());
+
+        let points = block_on(db.read_by_filter("point and siteRef", None)).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(block_on(db.read_by_filter("site or point", Some(1))).unwrap().len(), 1);
+
+        let temp = Token::Ref("@temp".to_string(), None);
+        block_on(db.his_write(&temp, vec![(ts(2), Token::Number(ZincNumber::new(2.0), "".into())),
+                                          (ts(1), Token::Number(ZincNumber::new(1.0), "".into()))])).unwrap();
+
+        let items = block_on(db.his_read(&temp, ts(0), ts(2))).unwrap();
+        assert_eq!(items, vec![(ts(1), Token::Number(ZincNumber::new(1.0), "".into()))]);
+
+        assert!(block_on(db.point_write(&temp, 8, None, "operator", None)).is_err());
+
+        let cmd = Token::Ref("@cmd".to_string(), None);
+        db.insert((cmd.clone(), vec![Tag::new_marker("point"), Tag::new_marker("writable")]));
+        block_on(db.point_write(&cmd, 8, Some(Token::EscapedString("on".into())), "operator", None)).unwrap();
+
+        // Refs are looked up with or without the @
+        let plain = block_on(db.read_by_ids(&[Token::Ref("temp".to_string(), None)])).unwrap();
+        assert_eq!(plain[0].as_ref().map(|e| e.0.clone()), Some(temp.clone()));
+
+        let found = block_on(db.read_by_filter("curVal == \"on\"", None)).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(block_on(db.point_write_array(&cmd)).unwrap().level(8).map(|l| l.who.clone()), Some("operator".to_string()));
+    }
+
+    #[test]
+    fn timed_write_test() {
+        let db = MemoryDatabase::new(vec![]);
+        let cmd = Token::Ref("@cmd".to_string(), None);
+        db.insert((cmd.clone(), vec![Tag::new_marker("point"), Tag::new_marker("writable")]));
+
+        let cur_val = || block_on(db.read_by_ids(std::slice::from_ref(&cmd))).unwrap()[0].as_ref()
+            .and_then(|(_, tags)| tags.iter().find(|t| t.get_id() == "curVal").and_then(|t| t.get_value::<Token>()));
+
+        block_on(db.point_write(&cmd, 16, Some(Token::EscapedString("off".into())), "operator", None)).unwrap();
+        block_on(db.point_write(&cmd, 8, Some(Token::EscapedString("on".into())), "operator", Some(Token::Number(ZincNumber::new(50.0), "ms".into())))).unwrap();
+        assert_eq!(cur_val(), Some(Token::EscapedString("on".into())));
+
+        // Reads fall back to level 16 once the override expires, without another write
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(cur_val(), Some(Token::EscapedString("off".into())));
+        assert_eq!(block_on(db.read_by_filter("writeLevel == 16", None)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn referrers_test() {
+        let db = MemoryDatabase::new(vec![
+            (Token::Ref("@site".to_string(), None), vec![Tag::new_marker("site")]),
+            (Token::Ref("@ahu".to_string(), None), vec![Tag::new_marker("equip"), Tag::new_ref("siteRef", "@site")]),
+            (Token::Ref("@temp".to_string(), None), vec![Tag::new_marker("point"), Tag::new_marker("alarm"), Tag::new_ref("equipRef", "@ahu")]),
+        ]);
+
+        let ids = |found: RefTags| found.into_iter().map(|(id, _)| id).collect::<Vec<Token>>();
+        let site = Token::Ref("@site".to_string(), None);
+        let ahu = Token::Ref("@ahu".to_string(), None);
+
+        assert_eq!(ids(block_on(db.read_referrers(&site, "siteRef")).unwrap()), vec![ahu.clone()]);
+        assert_eq!(ids(block_on(db.read_referenced_by("point and alarm", "equipRef")).unwrap()), vec![ahu.clone()]);
+
+        // The index follows inserts and removes
+        let fan = Token::Ref("@fan".to_string(), None);
+        db.insert((fan.clone(), vec![Tag::new_marker("equip"), Tag::new_ref("siteRef", "site")]));
+        assert_eq!(ids(block_on(db.read_referrers(&site, "siteRef")).unwrap()), vec![ahu.clone(), fan]);
+
+        db.remove(&Token::Ref("temp".to_string(), None));
+        assert!(block_on(db.read_referenced_by("point", "equipRef")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+//! The nav op, browsing the database as a tree.
+//!
+//! The roots are the sites, a site's children are the equips with a `siteRef` to it and an equip's
+//! children are the points with an `equipRef` to it. Rows with children carry a `navId` to pass
+//! back in the next request. A `HaystackDatabase` can replace the tree by overriding `nav`.
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::filter::{RefTag, RefTags};
+use crate::token::*;
+
+use super::database::HaystackDatabase;
+use super::auth::Identity;
+use super::format::Format;
+use super::policy::{Policy, PolicyDatabase};
+use super::read::entities_to_grid;
+use super::{bad_request, error_reply, request_value};
+
+/// The navId of a request grid. `None` if the request is invalid, `Some(None)` for the roots.
+pub fn nav_id_from_grid(grid: &Grid) -> Option<Option<Token>> {
+    match request_value(grid, "navId") {
+        Some(Token::Ref(id, dis)) => Some(Some(Token::Ref(id, dis))),
+        Some(Token::Null) | Some(Token::Empty) | None => Some(None),
+        Some(_) => None,
+    }
+}
+
+fn has_marker(entity: &RefTag, name: &str) -> bool {
+    entity.1.contains(&Tag::new_marker(name))
+}
+
+/// The entities with the marker `kind` whose `ref_tag` points at `parent`.
+async fn children_via<D: HaystackDatabase>(db: &D, kind: &str, ref_tag: &str, parent: &Token) -> HaystackResult<RefTags> {
+    let referrers = db.read_referrers(parent, ref_tag).await?;
+    Ok(referrers.into_iter().filter(|e| has_marker(e, kind)).collect())
+}
+
+/// Rows for `entities`, with a `navId` for those that have children.
+fn nav_rows(entities: RefTags, navigable: bool) -> Grid {
+    let entities: Vec<Option<RefTag>> = entities.into_iter().map(|(id, mut tags)| {
+        if navigable {
+            tags.push(Tag::new_from_token(Token::Id("navId".into()), id.clone()));
+        }
+        Some((id, tags))
+    }).collect();
+
+    entities_to_grid(&entities)
+}
+
+/// The children of `nav_id`, or the sites if it is `None`.
+pub async fn nav_grid<D: HaystackDatabase>(db: &D, nav_id: Option<&Token>) -> HaystackResult<Grid> {
+
+    if let Some(grid) = db.nav(nav_id).await? {
+        return Ok(grid);
+    }
+
+    let nav_id = match nav_id {
+        Some(nav_id) => nav_id,
+        None => return Ok(nav_rows(db.read_by_filter("site", None).await?, true)),
+    };
+
+    let parent = match db.read_by_ids(std::slice::from_ref(nav_id)).await?.pop().flatten() {
+        Some(parent) => parent,
+        None => return Ok(Grid::empty()),
+    };
+
+    if has_marker(&parent, "site") {
+        Ok(nav_rows(children_via(db, "equip", "siteRef", nav_id).await?, true))
+    }
+    else if has_marker(&parent, "equip") {
+        Ok(nav_rows(children_via(db, "point", "equipRef", nav_id).await?, false))
+    }
+    else {
+        Ok(Grid::empty())
+    }
+}
+
+pub async fn nav<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    policy: Arc<Policy>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    let db = PolicyDatabase::new(db, policy, &identity);
+
+    // No request grid navigates from the root
+    let nav_id = match request.as_ref().map_or(Some(None), nav_id_from_grid) {
+        Some(nav_id) => nav_id,
+        None => return Ok(bad_request(&format, "Request must have a navId ref")),
+    };
+
+    match nav_grid(&db, nav_id.as_ref()).await {
+        Ok(grid) => Ok(format.reply(&grid, http::StatusCode::OK)),
+        Err(e) => {
+            debug!("nav failed: {}", e);
+            Ok(error_reply(&format, &e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hval::HVal;
+    use crate::server::MemoryDatabase;
+    use futures::executor::block_on;
+
+    #[test]
+    fn nav_test() {
+        let db = MemoryDatabase::new(vec![
+            (Token::Ref("@site".to_string(), None), vec![Tag::new_marker("site")]),
+            (Token::Ref("@ahu".to_string(), None), vec![Tag::new_marker("equip"), Tag::new_ref("siteRef", "@site")]),
+            (Token::Ref("@temp".to_string(), None), vec![Tag::new_marker("point"), Tag::new_ref("equipRef", "@ahu"), Tag::new_ref("siteRef", "@site")]),
+            (Token::Ref("@other".to_string(), None), vec![Tag::new_marker("equip"), Tag::new_ref("siteRef", "@elsewhere")]),
+        ]);
+
+        assert_eq!(block_on(nav_grid(&db, None)).unwrap().to_zinc(), "ver:\"3.0\"\nid,navId,site\n@site,@site,M");
+
+        let site = Token::Ref("@site".to_string(), None);
+        assert_eq!(block_on(nav_grid(&db, Some(&site))).unwrap().to_zinc(), "ver:\"3.0\"\nid,equip,navId,siteRef\n@ahu,M,@ahu,@site");
+
+        let ahu = Token::Ref("@ahu".to_string(), None);
+        assert_eq!(block_on(nav_grid(&db, Some(&ahu))).unwrap().to_zinc(), "ver:\"3.0\"\nid,equipRef,point,siteRef\n@temp,@ahu,M,@site");
+
+        let temp = Token::Ref("@temp".to_string(), None);
+        assert_eq!(block_on(nav_grid(&db, Some(&temp))).unwrap().rows.len(), 0);
+    }
+}
@@ -0,0 +1,310 @@
+//! The pointWrite op and the priority array of writable points.
+//!
+//! A writable point has 16 priority levels. Level 1 is the highest priority and level 16 the
+//! lowest. The value the point is commanded to, the effective value, is the value of the highest
+//! priority level that is set.
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, Utc};
+
+use crate::error::*;
+use crate::hval::HVal;
+use crate::token::*;
+use crate::units;
+
+use super::database::HaystackDatabase;
+use super::format::Format;
+use super::policy::{Policy, PolicyDatabase};
+use super::auth::Identity;
+use super::{bad_request, error_reply, request_value};
+
+/// A value written at one level of a priority array.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorityLevel {
+    pub val: Token,
+    pub who: String,
+    /// How long the value applies for, ie `30min`. `None` is until it is released.
+    pub duration: Option<Token>,
+    pub ts: DateTime<FixedOffset>,
+}
+
+/// The seconds of a write duration, a number in seconds or with a time unit such as `30min`.
+/// `None` if it is not a time, not finite or negative.
+pub fn duration_secs(duration: &Token) -> Option<f64> {
+    let secs = match duration {
+        Token::Number(n, unit) if unit.is_empty() => n.number,
+        Token::Number(n, unit) => units::convert(n.number, unit, "s")?,
+        _ => return None,
+    };
+
+    Some(secs).filter(|secs| secs.is_finite() && *secs >= 0.0)
+}
+
+impl PriorityLevel {
+    /// When the value stops applying, if it has a time `duration` that ends before the end of
+    /// time.
+    pub fn expires(&self) -> Option<DateTime<FixedOffset>> {
+        let secs = duration_secs(self.duration.as_ref()?)?;
+        self.ts.checked_add_signed(chrono::Duration::milliseconds((secs * 1000.0) as i64))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorityArray {
+    levels: Vec<Option<PriorityLevel>>,
+}
+
+impl Default for PriorityArray {
+    fn default() -> Self {
+        PriorityArray::new()
+    }
+}
+
+fn level_dis(level: u8) -> String {
+    match level {
+        1 => "emergency".to_string(),
+        8 => "manual".to_string(),
+        16 => "default".to_string(),
+        _ => level.to_string(),
+    }
+}
+
+impl PriorityArray {
+    pub const LEVELS: u8 = 16;
+
+    pub fn new() -> Self {
+        PriorityArray { levels: vec![None; PriorityArray::LEVELS as usize] }
+    }
+
+    /// Writes `val` at `level`, or releases the level if `val` is `None`.
+    pub fn write(&mut self, level: u8, val: Option<Token>, who: &str, duration: Option<Token>, ts: DateTime<FixedOffset>) -> HaystackResult<()> {
+        if !(1..=PriorityArray::LEVELS).contains(&level) {
+            return Err(HaystackError::GeneralError(format!("Invalid priority level {}", level)));
+        }
+
+        if let Some(duration) = duration.as_ref().filter(|d| duration_secs(d).is_none()) {
+            return Err(HaystackError::GeneralError(format!("Invalid duration {}", duration.to_zinc())));
+        }
+
+        self.levels[level as usize - 1] = val.map(|val| PriorityLevel { val, who: who.to_string(), duration, ts });
+
+        Ok(())
+    }
+
+    /// Whether any level that is set stops applying after a duration.
+    pub fn has_timed_levels(&self) -> bool {
+        self.levels.iter().flatten().any(|l| l.expires().is_some())
+    }
+
+    pub fn level(&self, level: u8) -> Option<&PriorityLevel> {
+        self.levels.get((level as usize).checked_sub(1)?)?.as_ref()
+    }
+
+    /// The level and value of the highest priority level that is set and has not expired at `now`.
+    pub fn effective(&self, now: DateTime<FixedOffset>) -> Option<(u8, &Token)> {
+        self.levels.iter().enumerate()
+            .filter_map(|(i, l)| l.as_ref().map(|l| (i as u8 + 1, l)))
+            .find(|(_, l)| l.expires().is_none_or(|expires| expires > now))
+            .map(|(level, l)| (level, &l.val))
+    }
+
+    /// A row per level with columns level, levelDis, val, who, duration and ts.
+    pub fn to_grid(&self) -> Grid {
+        let cols = Cols::new(["level", "levelDis", "val", "who", "duration", "ts"].iter()
+            .map(|name| Col::new(Token::Id(name.to_string()), None))
+            .collect());
+
+        let rows = (1..=PriorityArray::LEVELS).map(|level| {
+            let mut cells = vec![Token::Number(ZincNumber::new(level as f64), "".into()), Token::EscapedString(level_dis(level))];
+
+            match self.level(level) {
+                Some(l) => cells.extend(vec![l.val.clone(), Token::EscapedString(l.who.clone()),
+                                             l.duration.clone().unwrap_or(Token::Empty), Token::DateTime(l.ts, None)]),
+                None => cells.extend(vec![Token::Empty; 4]),
+            }
+
+            Row::new(cells.into_iter().map(|t| Val::new(Box::new(t))).collect())
+        }).collect();
+
+        Grid::new(GridMeta::new(Token::Ver("3.0".into()), None), cols, Rows::new(rows))
+    }
+}
+
+/// Sets the tag `name` to `value`, or removes it if `value` is `None`.
+pub(crate) fn set_tag(tags: &mut Vec<Tag>, name: &str, value: Option<Token>) {
+    tags.retain(|t| t.get_id() != name);
+
+    if let Some(value) = value {
+        tags.push(Tag::new_from_token(Token::Id(name.to_string()), value));
+    }
+}
+
+/// Sets the `writeVal`, `writeLevel` and `curVal` tags of a point from its priority array.
+pub fn apply_priority_array(tags: &mut Vec<Tag>, array: &PriorityArray, now: DateTime<FixedOffset>) {
+    let effective = array.effective(now);
+
+    set_tag(tags, "writeVal", effective.map(|(_, val)| val.clone()));
+    set_tag(tags, "writeLevel", effective.map(|(level, _)| Token::Number(ZincNumber::new(level as f64), "".into())));
+    set_tag(tags, "curVal", effective.map(|(_, val)| val.clone()));
+}
+
+// Request: a grid with a single row and following columns:
+//
+// id: Ref of the writable point
+// level: Number from 1 to 16, leave out to read the priority array
+// val: value to write, null to release the level
+// who: optional Str of who is writing, defaults to the authenticated user
+// duration: optional Number with a time unit for how long the value applies
+//
+// ver:"3.0"
+// id,level,val,who,duration
+// @fanCmd,8,"on","operator",30min
+//
+// Response: empty grid for a write, the priority array for a read.
+pub async fn point_write<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    policy: Arc<Policy>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    let db = PolicyDatabase::new(db, policy, &identity);
+
+    let request = match request {
+        Some(g) => g,
+        None => return Ok(bad_request(&format, "Missing request grid")),
+    };
+
+    let id = match request_value(&request, "id") {
+        Some(Token::Ref(id, dis)) => Token::Ref(id, dis),
+        _ => return Ok(bad_request(&format, "Request must have an id ref")),
+    };
+
+    let level = match request_value(&request, "level") {
+        None | Some(Token::Null) | Some(Token::Empty) => None,
+        // Checked before the cast, which would turn 8.5 into 8 and 256 into 255
+        Some(Token::Number(n, _)) if n.number.fract() == 0.0 && (1.0..=PriorityArray::LEVELS as f64).contains(&n.number) => Some(n.number as u8),
+        Some(_) => return Ok(bad_request(&format, "level must be an integer from 1 to 16")),
+    };
+
+    let result = match level {
+        None => db.point_write_array(&id).await.map(|array| array.to_grid()),
+        Some(level) => {
+            let val = match request_value(&request, "val") {
+                None | Some(Token::Null) | Some(Token::Empty) => None,
+                Some(val) => Some(val),
+            };
+
+            let who = match request_value(&request, "who") {
+                Some(Token::EscapedString(who)) => who,
+                _ => identity.username,
+            };
+
+            let duration = match request_value(&request, "duration") {
+                None | Some(Token::Null) | Some(Token::Empty) => None,
+                Some(duration) if duration_secs(&duration).is_some() => Some(duration),
+                Some(_) => return Ok(bad_request(&format, "duration must be a number with a time unit")),
+            };
+
+            db.point_write(&id, level, val, &who, duration).await.map(|_| Grid::empty())
+        }
+    };
+
+    match result {
+        Ok(grid) => Ok(format.reply(&grid, http::StatusCode::OK)),
+        Err(e) => {
+            debug!("pointWrite failed: {}", e);
+            Ok(error_reply(&format, &e))
+        }
+    }
+}
+
+/// The current time for priority array timestamps.
+pub fn now() -> DateTime<FixedOffset> {
+    DateTime::<FixedOffset>::from(Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn ts(minute: u32) -> DateTime<FixedOffset> {
+        DateTime::<FixedOffset>::from(Utc.ymd(2020, 1, 1).and_hms(0, minute, 0))
+    }
+
+    #[test]
+    fn priority_array_test() {
+        let mut array = PriorityArray::new();
+        assert_eq!(array.effective(ts(0)), None);
+
+        array.write(16, Some(Token::EscapedString("off".into())), "schedule", None, ts(0)).unwrap();
+        array.write(8, Some(Token::EscapedString("on".into())), "operator", Some(Token::Number(ZincNumber::new(30.0), "min".into())), ts(0)).unwrap();
+        assert!(array.write(17, None, "operator", None, ts(0)).is_err());
+
+        assert_eq!(array.effective(ts(10)), Some((8, &Token::EscapedString("on".into()))));
+        // The manual override has expired
+        assert_eq!(array.effective(ts(30)), Some((16, &Token::EscapedString("off".into()))));
+
+        assert_eq!(array.level(8).map(|l| l.who.as_str()), Some("operator"));
+
+        array.write(8, None, "operator", None, ts(10)).unwrap();
+        assert_eq!(array.effective(ts(10)), Some((16, &Token::EscapedString("off".into()))));
+
+        // Durations must be times, and ones past the end of time never expire
+        assert!(array.write(8, Some(Token::EscapedString("on".into())), "operator", Some(Token::Number(ZincNumber::new(5.0), "kg".into())), ts(0)).is_err());
+        assert!(array.write(8, Some(Token::EscapedString("on".into())), "operator", Some(Token::Number(ZincNumber::new(-1.0), "min".into())), ts(0)).is_err());
+
+        for secs in [1e14, 1e20] {
+            array.write(8, Some(Token::EscapedString("on".into())), "operator", Some(Token::Number(ZincNumber::new(secs), "s".into())), ts(0)).unwrap();
+            assert_eq!(array.level(8).unwrap().expires(), None);
+            assert_eq!(array.effective(ts(10)), Some((8, &Token::EscapedString("on".into()))));
+        }
+
+        array.write(8, None, "operator", None, ts(10)).unwrap();
+
+        let grid = array.to_grid();
+        assert_eq!(grid.rows.len(), 16);
+
+        let mut tags = vec![Tag::new_marker("point"), Tag::new_marker("writable")];
+        apply_priority_array(&mut tags, &array, ts(10));
+        assert_eq!(get_tag_value(&tags, "curVal"), Some(Token::EscapedString("off".into())));
+        assert_eq!(get_tag_value(&tags, "writeLevel"), Some(Token::Number(ZincNumber::new(16.0), "".into())));
+    }
+
+    #[test]
+    fn point_write_request_test() {
+        use crate::server::{Formats, MemoryDatabase};
+        use crate::zinc_tokenizer::grid;
+        use futures::executor::block_on;
+        use warp::Reply;
+
+        let db = Arc::new(MemoryDatabase::new(vec![
+            (Token::Ref("@cmd".to_string(), None), vec![Tag::new_marker("point"), Tag::new_marker("writable")]),
+        ]));
+
+        let identity = Identity { username: "operator".to_string(), roles: vec![], expires: 0, token_id: "jti".to_string() };
+        let format = Formats::new().encoder(None).unwrap();
+
+        let status = |request: &str| {
+            let (_, request) = grid(request).unwrap();
+            block_on(point_write(identity.clone(), format, Some(request), Arc::new(Policy::default()), db.clone())).unwrap().into_response().status()
+        };
+
+        assert_eq!(status("ver:\"3.0\"\nid,level,val,duration\n@cmd,8,\"on\",30min\n"), http::StatusCode::OK);
+        assert_eq!(status("ver:\"3.0\"\nid,level,val,duration\n@cmd,8,\"on\",5kg\n"), http::StatusCode::BAD_REQUEST);
+        assert_eq!(status("ver:\"3.0\"\nid,level,val,duration\n@cmd,8,\"on\",\"soon\"\n"), http::StatusCode::BAD_REQUEST);
+
+        for level in ["8.5", "0", "17", "256", "1e9", "-1"] {
+            assert_eq!(status(&format!("ver:\"3.0\"\nid,level,val\n@cmd,{},\"on\"\n", level)), http::StatusCode::BAD_REQUEST, "level {}", level);
+        }
+
+        assert_eq!(status("ver:\"3.0\"\nid,level,val\n@cmd,16,\"off\"\n"), http::StatusCode::OK);
+    }
+
+    fn get_tag_value(tags: &[Tag], name: &str) -> Option<Token> {
+        tags.iter().find(|t| t.get_id() == name).and_then(|t| t.get_value::<Token>())
+    }
+}
@@ -0,0 +1,292 @@
+//! Which users may call which ops and see which entities.
+//!
+//! Users are granted ops by their roles. An op that the policy does not list is open to every
+//! authenticated user, and users with the `admin` role may call every op.
+//!
+//! A role can also have a filter, which limits the entities its users read to those matching it.
+//! A user with several filtered roles sees the entities matching any of them. Every op reads
+//! through a `PolicyDatabase`, so the entities a user may not read are unknown to them.
+//!
+//! ```toml
+//! [policy.ops]
+//! hisWrite = ["operator"]
+//! pointWrite = ["operator"]
+//! invokeAction = ["operator"]
+//!
+//! [policy.filters]
+//! siteA = "siteRef==@siteA"
+//! ```
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+
+use crate::error::*;
+use crate::filter::{filter_eval_str, RefTag, RefTags};
+use crate::hval::HVal;
+use crate::token::*;
+
+use super::auth::Identity;
+use super::database::{referenced_by, HaystackDatabase, HisItem};
+use super::point::PriorityArray;
+
+/// The role allowed every op.
+pub const ADMIN: &str = "admin";
+
+/// The role of users that write points and history and invoke actions.
+pub const OPERATOR: &str = "operator";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// op -> the roles allowed to call it.
+    pub ops: HashMap<String, Vec<String>>,
+    /// role -> the filter the entities its users read must match.
+    pub filters: HashMap<String, String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        let ops = ["hisWrite", "pointWrite", "invokeAction"].iter()
+            .map(|op| (op.to_string(), vec![OPERATOR.to_string()]))
+            .collect();
+
+        Policy { ops, filters: HashMap::new() }
+    }
+}
+
+impl Policy {
+    /// Whether `identity` may call `op`.
+    pub fn allows(&self, op: &str, identity: &Identity) -> bool {
+        if identity.has_role(ADMIN) {
+            return true;
+        }
+
+        match self.ops.get(op) {
+            Some(roles) => roles.iter().any(|role| identity.has_role(role)),
+            None => true,
+        }
+    }
+
+    /// The filters of the roles of `identity`. Users without any are not restricted.
+    pub fn read_filters(&self, identity: &Identity) -> Vec<&str> {
+        identity.roles.iter().filter_map(|role| self.filters.get(role)).map(|f| f.as_str()).collect()
+    }
+
+    /// `entities` without the ones `identity` may not read.
+    pub fn restrict(&self, identity: &Identity, entities: RefTags) -> HaystackResult<RefTags> {
+        let filters = self.read_filters(identity);
+
+        if filters.is_empty() {
+            return Ok(entities);
+        }
+
+        let mut visible: RefTags = vec![];
+
+        for filter in filters {
+            for entity in filter_eval_str(filter, &entities)? {
+                if !visible.iter().any(|(id, _)| *id == entity.0) {
+                    visible.push(entity);
+                }
+            }
+        }
+
+        // In the order they were read
+        Ok(entities.into_iter().filter(|(id, _)| visible.iter().any(|(v, _)| v == id)).collect())
+    }
+
+    /// `entities` with the ones `identity` may not read replaced by `None`, as if they did not
+    /// exist.
+    pub fn restrict_ids(&self, identity: &Identity, entities: Vec<Option<RefTag>>) -> HaystackResult<Vec<Option<RefTag>>> {
+        if self.read_filters(identity).is_empty() {
+            return Ok(entities);
+        }
+
+        let visible = self.restrict(identity, entities.iter().flatten().cloned().collect())?;
+
+        Ok(entities.into_iter()
+            .map(|entity| entity.filter(|(id, _)| visible.iter().any(|(v, _)| v == id)))
+            .collect())
+    }
+
+    /// Checks that the filters parse.
+    pub fn validate(&self) -> HaystackResult<()> {
+        for (role, filter) in &self.filters {
+            if let Err(e) = filter_eval_str(filter, &vec![]) {
+                return Err(HaystackError::GeneralError(format!("Invalid filter of role {}: {}", role, e)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The entities of a database as `identity` sees them under a policy. Entities it may not read
+/// are left out of filter reads, read as `None` by id and fail other ops as unknown records.
+pub struct PolicyDatabase<D: HaystackDatabase> {
+    db: Arc<D>,
+    policy: Arc<Policy>,
+    identity: Identity,
+}
+
+impl<D: HaystackDatabase> PolicyDatabase<D> {
+    pub fn new(db: Arc<D>, policy: Arc<Policy>, identity: &Identity) -> Self {
+        PolicyDatabase { db, policy, identity: identity.clone() }
+    }
+
+    fn restricted(&self) -> bool {
+        !self.policy.read_filters(&self.identity).is_empty()
+    }
+
+    /// Fails with the first of `ids` that `identity` may not read.
+    async fn check_visible(&self, ids: &[Token]) -> HaystackResult<()> {
+        if !self.restricted() {
+            return Ok(());
+        }
+
+        match ids.iter().zip(self.read_by_ids(ids).await?).find(|(_, entity)| entity.is_none()) {
+            Some((id, _)) => Err(HaystackError::UnknownRecord(id.to_zinc())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<D: HaystackDatabase> HaystackDatabase for PolicyDatabase<D> {
+
+    async fn read_by_ids(&self, ids: &[Token]) -> HaystackResult<Vec<Option<RefTag>>> {
+        self.policy.restrict_ids(&self.identity, self.db.read_by_ids(ids).await?)
+    }
+
+    async fn read_by_filter(&self, filter: &str, limit: Option<usize>) -> HaystackResult<RefTags> {
+        if !self.restricted() {
+            return self.db.read_by_filter(filter, limit).await;
+        }
+
+        // Limited after the policy so restricted users still get up to `limit` entities
+        let mut found = self.policy.restrict(&self.identity, self.db.read_by_filter(filter, None).await?)?;
+
+        if let Some(limit) = limit {
+            found.truncate(limit);
+        }
+
+        Ok(found)
+    }
+
+    async fn read_referrers(&self, target: &Token, ref_tag: &str) -> HaystackResult<RefTags> {
+        self.policy.restrict(&self.identity, self.db.read_referrers(target, ref_tag).await?)
+    }
+
+    async fn read_referenced_by(&self, filter: &str, ref_tag: &str) -> HaystackResult<RefTags> {
+        if !self.restricted() {
+            return self.db.read_referenced_by(filter, ref_tag).await;
+        }
+
+        // Only the refs of the entities the user reads count
+        referenced_by(self, filter, ref_tag).await
+    }
+
+    async fn nav(&self, nav_id: Option<&Token>) -> HaystackResult<Option<Grid>> {
+        // The server builds the tree of a restricted user from the entities they read
+        if self.restricted() {
+            return Ok(None);
+        }
+
+        self.db.nav(nav_id).await
+    }
+
+    async fn his_read(&self, id: &Token, start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> HaystackResult<Vec<HisItem>> {
+        self.check_visible(std::slice::from_ref(id)).await?;
+        self.db.his_read(id, start, end).await
+    }
+
+    async fn his_write(&self, id: &Token, items: Vec<HisItem>) -> HaystackResult<()> {
+        self.check_visible(std::slice::from_ref(id)).await?;
+        self.db.his_write(id, items).await
+    }
+
+    async fn his_read_many(&self, ids: &[Token], start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> HaystackResult<Vec<Vec<HisItem>>> {
+        self.check_visible(ids).await?;
+        self.db.his_read_many(ids, start, end).await
+    }
+
+    async fn his_write_many(&self, writes: Vec<(Token, Vec<HisItem>)>) -> HaystackResult<()> {
+        let ids: Vec<Token> = writes.iter().map(|(id, _)| id.clone()).collect();
+        self.check_visible(&ids).await?;
+        self.db.his_write_many(writes).await
+    }
+
+    async fn point_write(&self, id: &Token, level: u8, val: Option<Token>, who: &str, duration: Option<Token>) -> HaystackResult<()> {
+        self.check_visible(std::slice::from_ref(id)).await?;
+        self.db.point_write(id, level, val, who, duration).await
+    }
+
+    async fn point_write_array(&self, id: &Token) -> HaystackResult<PriorityArray> {
+        self.check_visible(std::slice::from_ref(id)).await?;
+        self.db.point_write_array(id).await
+    }
+
+    async fn watch_sub(&self, ids: &[Token]) -> HaystackResult<()> {
+        self.check_visible(ids).await?;
+        self.db.watch_sub(ids).await
+    }
+
+    async fn watch_unsub(&self, ids: &[Token]) -> HaystackResult<()> {
+        self.db.watch_unsub(ids).await
+    }
+
+    async fn invoke_action(&self, id: &Token, action: &str, args: Vec<Tag>) -> HaystackResult<Grid> {
+        self.check_visible(std::slice::from_ref(id)).await?;
+        self.db.invoke_action(id, action, args).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::*;
+
+    fn identity(roles: &[&str]) -> Identity {
+        Identity { username: "user".to_string(), roles: roles.iter().map(|r| r.to_string()).collect(), expires: 0, token_id: "jti".to_string() }
+    }
+
+    fn entity(id: &str, site: &str) -> RefTag {
+        (Token::Ref(id.to_string(), None), vec![Tag::new_marker("point"), Tag::new_ref("siteRef", site)])
+    }
+
+    #[test]
+    fn allows_test() {
+        let policy = Policy::default();
+
+        assert!(policy.allows("read", &identity(&[])));
+        assert!(!policy.allows("hisWrite", &identity(&[])));
+        assert!(!policy.allows("pointWrite", &identity(&["viewer"])));
+        assert!(policy.allows("invokeAction", &identity(&["viewer", "operator"])));
+        assert!(policy.allows("invokeAction", &identity(&["admin"])));
+    }
+
+    #[test]
+    fn restrict_test() {
+        let mut policy = Policy::default();
+        policy.filters.insert("siteA".to_string(), "siteRef==@siteA".to_string());
+        policy.filters.insert("siteB".to_string(), "siteRef==@siteB".to_string());
+        policy.validate().unwrap();
+
+        let entities = vec![entity("@a1", "@siteA"), entity("@b1", "@siteB"), entity("@c1", "@siteC")];
+
+        let ids = |found: RefTags| found.into_iter().map(|(id, _)| id).collect::<Vec<Token>>();
+
+        assert_eq!(policy.restrict(&identity(&["operator"]), entities.clone()).unwrap().len(), 3);
+        assert_eq!(ids(policy.restrict(&identity(&["siteA"]), entities.clone()).unwrap()), vec![Token::Ref("@a1".to_string(), None)]);
+        assert_eq!(ids(policy.restrict(&identity(&["siteB", "siteA"]), entities.clone()).unwrap()),
+                   vec![Token::Ref("@a1".to_string(), None), Token::Ref("@b1".to_string(), None)]);
+
+        let read = policy.restrict_ids(&identity(&["siteA"]), vec![Some(entity("@b1", "@siteB")), None, Some(entity("@a1", "@siteA"))]).unwrap();
+        assert_eq!(read, vec![None, None, Some(entity("@a1", "@siteA"))]);
+
+        policy.filters.insert("bad".to_string(), "siteRef==".to_string());
+        assert!(policy.validate().is_err());
+    }
+}
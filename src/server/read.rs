@@ -0,0 +1,138 @@
+//! The read op, reading entities by filter or by id.
+//!
+//! Request: a grid with a `filter` column and optional `limit`, or an `id` column with one row per
+//! entity to read. Response: a grid with a row per entity and a column per tag.
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use crate::filter::RefTag;
+use crate::token::*;
+
+use super::database::HaystackDatabase;
+use super::auth::Identity;
+use super::format::Format;
+use super::policy::{Policy, PolicyDatabase};
+use super::{bad_request, error_reply, request_ids, request_value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadRequest {
+    Filter(String, Option<usize>),
+    Ids(Vec<Token>),
+}
+
+fn to_limit(t: &Token) -> Option<usize> {
+    match t {
+        Token::Number(n, _) if n.number >= 0.0 => Some(n.number as usize),
+        _ => None,
+    }
+}
+
+impl ReadRequest {
+
+    pub fn from_grid(grid: &Grid) -> Option<ReadRequest> {
+
+        if let Some(Token::EscapedString(filter)) = request_value(grid, "filter") {
+            let limit = request_value(grid, "limit").as_ref().and_then(to_limit);
+            return Some(ReadRequest::Filter(filter, limit));
+        }
+
+        request_ids(grid).map(ReadRequest::Ids)
+    }
+}
+
+/// Builds a grid with a row per entity. Columns are `id` followed by the other tag names sorted.
+/// `None` entities, ie unknown ids, become rows of nulls.
+pub fn entities_to_grid(entities: &[Option<RefTag>]) -> Grid {
+
+    let names: BTreeSet<String> = entities.iter().flatten()
+        .flat_map(|(_, tags)| tags.iter().map(|t| t.get_id()))
+        .filter(|name| name != "id")
+        .collect();
+
+    let names: Vec<String> = std::iter::once("id".to_string()).chain(names).collect();
+
+    let cols = Cols::new(names.iter().map(|n| Col::new(Token::Id(n.to_string()), None)).collect());
+
+    let rows: Vec<Row> = entities.iter().map(|entity| {
+        match entity {
+            Some((id, tags)) => Row::new(names.iter().map(|name| {
+                if name == "id" {
+                    return Val::new(Box::new(id.clone()));
+                }
+
+                match tags.iter().find(|t| &t.get_id() == name) {
+                    Some(tag) => tag.value.clone().unwrap_or_else(|| Val::new(Box::new(Token::Marker))),
+                    None => Val::new(Box::new(Token::Empty)),
+                }
+            }).collect()),
+            None => Row::new(names.iter().map(|_| Val::new(Box::new(Token::Null))).collect()),
+        }
+    }).collect();
+
+    Grid::new(GridMeta::new(Token::Ver("3.0".into()), None), cols, Rows::new(rows))
+}
+
+/// Entities the policy does not let `identity` read are left out of filter reads and read as
+/// unknown by id.
+pub async fn read<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    policy: Arc<Policy>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    let db = PolicyDatabase::new(db, policy, &identity);
+
+    let result = match request.as_ref().and_then(ReadRequest::from_grid) {
+        Some(ReadRequest::Filter(filter, limit)) => db.read_by_filter(&filter, limit).await
+            .map(|found| found.into_iter().map(Some).collect::<Vec<Option<RefTag>>>()),
+        Some(ReadRequest::Ids(ids)) => db.read_by_ids(&ids).await,
+        None => return Ok(bad_request(&format, "Request must have a filter or ids")),
+    };
+
+    match result {
+        Ok(entities) => Ok(format.reply(&entities_to_grid(&entities), http::StatusCode::OK)),
+        Err(e) => {
+            debug!("read failed: {}", e);
+            Ok(error_reply(&format, &e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hval::HVal;
+    use crate::zinc_tokenizer::grid;
+    use crate::server::format::grid_from_query;
+
+    #[test]
+    fn read_request_test() {
+        let (_, g) = grid("ver:\"3.0\"\nfilter,limit\n\"point and his\",10\n").unwrap();
+        assert_eq!(ReadRequest::from_grid(&g), Some(ReadRequest::Filter("point and his".to_string(), Some(10))));
+
+        let (_, g) = grid("ver:\"3.0\"\nid\n@a\n@b\n").unwrap();
+        assert_eq!(ReadRequest::from_grid(&g), Some(ReadRequest::Ids(vec![Token::Ref("@a".to_string(), None), Token::Ref("@b".to_string(), None)])));
+
+        // GET /read?id=@a and /read?filter=site&limit=x
+        let params = |q: &[(&str, &str)]| q.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect::<Vec<(String, String)>>();
+
+        let g = grid_from_query(&params(&[("id", "@a")])).unwrap();
+        assert_eq!(ReadRequest::from_grid(&g), Some(ReadRequest::Ids(vec![Token::Ref("@a".to_string(), None)])));
+
+        let g = grid_from_query(&params(&[("filter", "site"), ("limit", "x")])).unwrap();
+        assert_eq!(ReadRequest::from_grid(&g), Some(ReadRequest::Filter("site".to_string(), None)));
+    }
+
+    #[test]
+    fn entities_to_grid_test() {
+        let entities = vec![
+            Some((Token::Ref("@a".to_string(), None), vec![Tag::new_marker("site"), Tag::new_string("dis", "A")])),
+            None,
+        ];
+
+        assert_eq!(entities_to_grid(&entities).to_zinc(), "ver:\"3.0\"\nid,dis,site\n@a,\"A\",M\nN,N,N");
+    }
+}
@@ -0,0 +1,492 @@
+//! SCRAM authentication, RFC 5802 with the SHA-256 and SHA-512 hashes of RFC 7677.
+//!
+//! A `Handshake` is the server side of one exchange. It is started with the
+//! client-first-message, which it answers with the server-first-message, and finished with the
+//! client-final-message, which it answers with the server-final-message once the client proof
+//! checks out. Channel binding is not supported.
+//!
+//! `Handshakes` keeps the exchanges in progress under handshake tokens the server hands out in
+//! reply to HELLO, and drops them when they time out. The exchanges in progress are capped per
+//! address and in total, so a flood of HELLOs cannot grow them without bound.
+use std::collections::HashMap;
+use std::iter;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+use data_encoding::BASE64;
+use parking_lot::Mutex;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScramHash {
+    #[default]
+    #[serde(rename = "SHA-256")]
+    Sha256,
+    #[serde(rename = "SHA-512")]
+    Sha512,
+}
+
+fn invalid(message: &str) -> HaystackError {
+    HaystackError::GeneralError(format!("Invalid SCRAM message: {}", message))
+}
+
+impl ScramHash {
+    /// The name used in the `hash` parameter of the auth headers, ie `SHA-256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ScramHash::Sha256 => "SHA-256",
+            ScramHash::Sha512 => "SHA-512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ScramHash::Sha256, ScramHash::Sha512].iter().copied().find(|h| h.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn hmac_algorithm(self) -> ring::hmac::Algorithm {
+        match self {
+            ScramHash::Sha256 => ring::hmac::HMAC_SHA256,
+            ScramHash::Sha512 => ring::hmac::HMAC_SHA512,
+        }
+    }
+
+    /// SaltedPassword := Hi(Normalize(password), salt, i)
+    pub fn salted_password(self, password: &str, salt: &[u8], iterations: u32) -> HaystackResult<Vec<u8>> {
+        let password = stringprep::saslprep(password)
+            .map_err(|_| HaystackError::GeneralError("Password contains prohibited characters".to_string()))?;
+
+        let iterations = NonZeroU32::new(iterations)
+            .ok_or_else(|| HaystackError::GeneralError("Iteration count must be at least 1".to_string()))?;
+
+        let (algorithm, len) = match self {
+            ScramHash::Sha256 => (ring::pbkdf2::PBKDF2_HMAC_SHA256, ring::digest::SHA256_OUTPUT_LEN),
+            ScramHash::Sha512 => (ring::pbkdf2::PBKDF2_HMAC_SHA512, ring::digest::SHA512_OUTPUT_LEN),
+        };
+
+        let mut salted_password = vec![0u8; len];
+        ring::pbkdf2::derive(algorithm, iterations, salt, password.as_bytes(), &mut salted_password);
+
+        Ok(salted_password)
+    }
+
+    pub fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        ring::hmac::sign(&ring::hmac::Key::new(self.hmac_algorithm(), key), data).as_ref().to_vec()
+    }
+
+    pub fn hash(self, data: &[u8]) -> Vec<u8> {
+        let algorithm = match self {
+            ScramHash::Sha256 => &ring::digest::SHA256,
+            ScramHash::Sha512 => &ring::digest::SHA512,
+        };
+
+        ring::digest::digest(algorithm, data).as_ref().to_vec()
+    }
+
+    /// (StoredKey, ServerKey) for a password, which is what the server keeps instead of it.
+    pub fn keys(self, password: &str, salt: &[u8], iterations: u32) -> HaystackResult<(Vec<u8>, Vec<u8>)> {
+        let salted_password = self.salted_password(password, salt, iterations)?;
+        let client_key = self.hmac(&salted_password, b"Client Key");
+
+        Ok((self.hash(&client_key), self.hmac(&salted_password, b"Server Key")))
+    }
+}
+
+/// A nonce of printable characters.
+pub fn nonce() -> String {
+    iter::repeat(())
+        .map(|()| rand::thread_rng().sample(Alphanumeric))
+        .take(24)
+        .collect()
+}
+
+/// The attributes of a message, ie `[('n', "user"), ('r', "rOprNGfwEbeRWgbNEkqO")]`.
+fn attributes(message: &str) -> HaystackResult<Vec<(char, &str)>> {
+    message.split(',').map(|attr| {
+        let mut chars = attr.chars();
+
+        match (chars.next(), chars.next()) {
+            (Some(name), Some('=')) if name.is_ascii_alphabetic() => Ok((name, &attr[2..])),
+            _ => Err(invalid(attr)),
+        }
+    }).collect()
+}
+
+/// Decodes a saslname, where `,` and `=` are sent as `=2C` and `=3D`.
+fn saslname(s: &str) -> HaystackResult<String> {
+    let mut name = String::new();
+    let mut rest = s;
+
+    while let Some(i) = rest.find('=') {
+        name.push_str(&rest[..i]);
+
+        match rest.get(i..i + 3) {
+            Some("=2C") => name.push(','),
+            Some("=3D") => name.push('='),
+            _ => return Err(invalid("bad username escape")),
+        }
+
+        rest = &rest[i + 3..];
+    }
+
+    name.push_str(rest);
+
+    if name.is_empty() {
+        return Err(invalid("empty username"));
+    }
+
+    Ok(name)
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+/// The server side of one exchange.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub username: String,
+    pub hash: ScramHash,
+    gs2_header: String,
+    nonce: String,
+    client_first_bare: String,
+    server_first: String,
+}
+
+impl Handshake {
+    /// Starts an exchange with a client-first-message, ie `n,,n=user,r=rOprNGfwEbeRWgbNEkqO`, for
+    /// a user with `salt` and `iterations`. `server_nonce` is appended to the client nonce.
+    pub fn start(client_first: &str, hash: ScramHash, salt: &[u8], iterations: u32, server_nonce: &str) -> HaystackResult<Self> {
+        let mut parts = client_first.splitn(3, ',');
+
+        let (flag, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(flag), Some(authzid), Some(bare)) => (flag, authzid, bare),
+            _ => return Err(invalid("missing gs2 header")),
+        };
+
+        if flag != "n" && flag != "y" {
+            return Err(invalid("channel binding is not supported"));
+        }
+
+        let attrs = attributes(bare)?;
+
+        let (username, client_nonce) = match attrs.as_slice() {
+            [('n', username), ('r', client_nonce), ..] => (saslname(username)?, *client_nonce),
+            [('m', _), ..] => return Err(invalid("mandatory extensions are not supported")),
+            _ => return Err(invalid("expected n and r")),
+        };
+
+        if !authzid.is_empty() && authzid != format!("a={}", username) {
+            return Err(invalid("authorization identity differs from the username"));
+        }
+
+        if client_nonce.is_empty() || !client_nonce.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(invalid("bad client nonce"));
+        }
+
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!("r={},s={},i={}", nonce, BASE64.encode(salt), iterations);
+
+        Ok(Handshake {
+            username,
+            hash,
+            gs2_header: format!("{},{},", flag, authzid),
+            nonce,
+            client_first_bare: bare.to_string(),
+            server_first,
+        })
+    }
+
+    /// The reply to the client-first-message, ie `r=<client nonce><server nonce>,s=<salt>,i=4096`.
+    pub fn server_first(&self) -> &str {
+        &self.server_first
+    }
+
+    /// Checks the client proof of a client-final-message, ie `c=biws,r=<nonce>,p=<proof>`,
+    /// against the StoredKey of the user. Returns the server-final-message, `v=<signature>`.
+    pub fn finish(&self, client_final: &str, stored_key: &[u8], server_key: &[u8]) -> HaystackResult<String> {
+        let (without_proof, proof) = match client_final.rfind(",p=") {
+            Some(i) => (&client_final[..i], &client_final[i + 3..]),
+            None => return Err(invalid("missing proof")),
+        };
+
+        let attrs = attributes(without_proof)?;
+
+        let (binding, nonce) = match attrs.as_slice() {
+            [('c', binding), ('r', nonce), ..] => (*binding, *nonce),
+            _ => return Err(invalid("expected c, r and p")),
+        };
+
+        if BASE64.decode(binding.as_bytes()).ok().as_deref() != Some(self.gs2_header.as_bytes()) {
+            return Err(invalid("channel binding differs from the gs2 header"));
+        }
+
+        if nonce != self.nonce {
+            return Err(HaystackError::AuthError);
+        }
+
+        let proof = BASE64.decode(proof.as_bytes()).map_err(|_| invalid("bad proof"))?;
+
+        let auth_message = format!("{},{},{}", self.client_first_bare, self.server_first, without_proof);
+
+        // ClientKey := ClientProof XOR HMAC(StoredKey, AuthMessage), which is right if its hash is the StoredKey
+        let client_signature = self.hash.hmac(stored_key, auth_message.as_bytes());
+
+        if proof.len() != client_signature.len() {
+            return Err(HaystackError::AuthError);
+        }
+
+        let client_key = xor(&proof, &client_signature);
+
+        if ring::constant_time::verify_slices_are_equal(&self.hash.hash(&client_key), stored_key).is_err() {
+            return Err(HaystackError::AuthError);
+        }
+
+        let server_signature = self.hash.hmac(server_key, auth_message.as_bytes());
+
+        Ok(format!("v={}", BASE64.encode(&server_signature)))
+    }
+}
+
+struct Pending {
+    addr: Option<IpAddr>,
+    username: String,
+    hash: ScramHash,
+    handshake: Option<Handshake>,
+    started: Instant,
+}
+
+/// The exchanges in progress, by handshake token.
+pub struct Handshakes {
+    pending: Mutex<HashMap<String, Pending>>,
+    timeout: Duration,
+    max_per_addr: usize,
+    max_total: usize,
+}
+
+impl Default for Handshakes {
+    fn default() -> Self {
+        Handshakes::new(Handshakes::DEFAULT_TIMEOUT)
+    }
+}
+
+impl Handshakes {
+    /// How long a client has to finish an exchange after HELLO.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// How many exchanges one address can have in progress.
+    pub const DEFAULT_MAX_PER_ADDR: usize = 16;
+
+    /// How many exchanges can be in progress.
+    pub const DEFAULT_MAX_TOTAL: usize = 10000;
+
+    pub fn new(timeout: Duration) -> Self {
+        Handshakes {
+            pending: Mutex::new(HashMap::new()),
+            timeout,
+            max_per_addr: Handshakes::DEFAULT_MAX_PER_ADDR,
+            max_total: Handshakes::DEFAULT_MAX_TOTAL,
+        }
+    }
+
+    /// These handshakes with at most `per_addr` exchanges in progress per address and `total`
+    /// in all.
+    pub fn with_limits(mut self, per_addr: usize, total: usize) -> Self {
+        self.max_per_addr = per_addr;
+        self.max_total = total;
+        self
+    }
+
+    /// Starts an exchange for `username` from `addr`, returning the handshake token the client
+    /// sends back with its messages. Fails with how long to wait when the address or the server
+    /// has too many exchanges in progress.
+    pub fn hello(&self, addr: Option<IpAddr>, username: &str, hash: ScramHash) -> Result<String, Duration> {
+        self.sweep();
+
+        let mut pending = self.pending.lock();
+
+        // Until the oldest of the exchanges counted against the limit times out
+        let wait = |started: Option<Instant>| self.timeout.checked_sub(started.map(|s| s.elapsed()).unwrap_or_default()).unwrap_or_default();
+
+        if addr.is_some() {
+            let from_addr = || pending.values().filter(|p| p.addr == addr);
+
+            if from_addr().count() >= self.max_per_addr {
+                return Err(wait(from_addr().map(|p| p.started).min()));
+            }
+        }
+
+        if pending.len() >= self.max_total {
+            return Err(wait(pending.values().map(|p| p.started).min()));
+        }
+
+        let token = nonce();
+
+        pending.insert(token.clone(), Pending {
+            addr,
+            username: username.to_string(),
+            hash,
+            handshake: None,
+            started: Instant::now(),
+        });
+
+        Ok(token)
+    }
+
+    /// The username and hash of the exchange for `token`, if it has not timed out.
+    pub fn pending(&self, token: &str) -> Option<(String, ScramHash)> {
+        let pending = self.pending.lock();
+
+        pending.get(token)
+            .filter(|p| p.started.elapsed() < self.timeout)
+            .map(|p| (p.username.clone(), p.hash))
+    }
+
+    /// Records the handshake started by the client-first-message. Fails if the token is unknown,
+    /// has timed out or already has a handshake, or if the handshake is for another user.
+    pub fn first(&self, token: &str, handshake: Handshake) -> HaystackResult<()> {
+        let mut pending = self.pending.lock();
+
+        match pending.get_mut(token) {
+            Some(p) if p.started.elapsed() < self.timeout && p.handshake.is_none() && p.username == handshake.username => {
+                p.handshake = Some(handshake);
+                Ok(())
+            },
+            _ => {
+                pending.remove(token);
+                Err(HaystackError::AuthError)
+            }
+        }
+    }
+
+    /// Removes the exchange for `token`, returning its handshake if it has one and has not timed
+    /// out. A token can only be finished once.
+    pub fn take(&self, token: &str) -> Option<Handshake> {
+        self.pending.lock().remove(token)
+            .filter(|p| p.started.elapsed() < self.timeout)
+            .and_then(|p| p.handshake)
+    }
+
+    /// Drops the exchanges that have timed out.
+    pub fn sweep(&self) {
+        let timeout = self.timeout;
+        self.pending.lock().retain(|_, p| p.started.elapsed() < timeout);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The client-final-message for a password, as a client computes it.
+    fn client_final(hash: ScramHash, password: &str, handshake: &Handshake, salt: &[u8], iterations: u32) -> String {
+        let without_proof = format!("c=biws,r={}", handshake.nonce);
+        let auth_message = format!("{},{},{}", handshake.client_first_bare, handshake.server_first(), without_proof);
+
+        let salted_password = hash.salted_password(password, salt, iterations).unwrap();
+        let client_key = hash.hmac(&salted_password, b"Client Key");
+        let client_signature = hash.hmac(&hash.hash(&client_key), auth_message.as_bytes());
+
+        format!("{},p={}", without_proof, BASE64.encode(&xor(&client_key, &client_signature)))
+    }
+
+    #[test]
+    fn rfc7677_test() {
+        let salt = BASE64.decode(b"W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let (stored_key, server_key) = ScramHash::Sha256.keys("pencil", &salt, 4096).unwrap();
+
+        let handshake = Handshake::start("n,,n=user,r=rOprNGfwEbeRWgbNEkqO", ScramHash::Sha256, &salt, 4096, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0").unwrap();
+        assert_eq!(handshake.username, "user");
+        assert_eq!(handshake.server_first(), "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096");
+
+        let client_final = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+        assert_eq!(handshake.finish(client_final, &stored_key, &server_key).unwrap(), "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+
+        // Wrong password
+        let (stored_key, server_key) = ScramHash::Sha256.keys("pen", &salt, 4096).unwrap();
+        assert!(matches!(handshake.finish(client_final, &stored_key, &server_key), Err(HaystackError::AuthError)));
+    }
+
+    #[test]
+    fn handshake_test() {
+        let salt = b"salt";
+        let (stored_key, server_key) = ScramHash::Sha512.keys("pencil", salt, 4096).unwrap();
+
+        let handshake = Handshake::start("n,,n=a=2Cb,r=abc", ScramHash::Sha512, salt, 4096, &nonce()).unwrap();
+        assert_eq!(handshake.username, "a,b");
+
+        let message = client_final(ScramHash::Sha512, "pencil", &handshake, salt, 4096);
+        let server_final = handshake.finish(&message, &stored_key, &server_key).unwrap();
+        assert_eq!(server_final.len(), 2 + 88);
+
+        // The nonce must be the combined nonce
+        let replay = message.replace(&handshake.nonce, "abc");
+        assert!(handshake.finish(&replay, &stored_key, &server_key).is_err());
+
+        assert!(Handshake::start("p=tls-unique,,n=user,r=abc", ScramHash::Sha256, salt, 4096, "x").is_err());
+        assert!(Handshake::start("n,,m=ext,n=user,r=abc", ScramHash::Sha256, salt, 4096, "x").is_err());
+        assert!(Handshake::start("n,a=admin,n=user,r=abc", ScramHash::Sha256, salt, 4096, "x").is_err());
+        assert!(Handshake::start("n=user,r=abc", ScramHash::Sha256, salt, 4096, "x").is_err());
+
+        assert_eq!(ScramHash::from_name("sha-512"), Some(ScramHash::Sha512));
+        assert_eq!(ScramHash::from_name("MD5"), None);
+    }
+
+    #[test]
+    fn handshakes_test() {
+        let handshakes = Handshakes::default();
+        let token = handshakes.hello(None, "user", ScramHash::Sha256).unwrap();
+        assert_eq!(handshakes.pending(&token), Some(("user".to_string(), ScramHash::Sha256)));
+        assert_eq!(handshakes.pending("guessed"), None);
+
+        let other = Handshake::start("n,,n=other,r=abc", ScramHash::Sha256, b"salt", 4096, "x").unwrap();
+        assert!(handshakes.first(&token, other).is_err());
+        assert!(handshakes.is_empty());
+
+        let token = handshakes.hello(None, "user", ScramHash::Sha256).unwrap();
+        let handshake = Handshake::start("n,,n=user,r=abc", ScramHash::Sha256, b"salt", 4096, "x").unwrap();
+        handshakes.first(&token, handshake.clone()).unwrap();
+        assert!(handshakes.first(&token, handshake).is_err());
+
+        let token = handshakes.hello(None, "user", ScramHash::Sha256).unwrap();
+        handshakes.first(&token, Handshake::start("n,,n=user,r=abc", ScramHash::Sha256, b"salt", 4096, "x").unwrap()).unwrap();
+        assert!(handshakes.take(&token).is_some());
+        assert!(handshakes.take(&token).is_none());
+
+        let expired = Handshakes::new(Duration::from_secs(0));
+        let token = expired.hello(None, "user", ScramHash::Sha256).unwrap();
+        assert_eq!(expired.pending(&token), None);
+        expired.sweep();
+        assert!(expired.is_empty());
+
+        // HELLOs over the limits wait for the oldest exchange to time out
+        let limited = Handshakes::default().with_limits(2, 3);
+        let a = Some(IpAddr::from([10, 0, 0, 1]));
+        let b = Some(IpAddr::from([10, 0, 0, 2]));
+        limited.hello(a, "user", ScramHash::Sha256).unwrap();
+        limited.hello(a, "user", ScramHash::Sha256).unwrap();
+        let wait = limited.hello(a, "user", ScramHash::Sha256).unwrap_err();
+        assert!(wait > Duration::from_secs(0) && wait <= Handshakes::DEFAULT_TIMEOUT);
+
+        limited.hello(b, "user", ScramHash::Sha256).unwrap();
+        assert!(limited.hello(Some(IpAddr::from([10, 0, 0, 3])), "user", ScramHash::Sha256).is_err());
+        assert!(limited.hello(None, "user", ScramHash::Sha256).is_err());
+        assert_eq!(limited.len(), 3);
+
+        let expired = Handshakes::new(Duration::from_secs(0)).with_limits(1, 1);
+        expired.hello(a, "user", ScramHash::Sha256).unwrap();
+        expired.hello(a, "user", ScramHash::Sha256).unwrap();
+    }
+}
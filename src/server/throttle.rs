@@ -0,0 +1,182 @@
+//! Failed login throttling.
+//!
+//! Failures are counted per username and per client address. After each failure the username
+//! and address wait for a backoff that doubles with every failure, and after `lockout_after`
+//! failures in a row they are locked out for `lockout` seconds or until an admin unlocks them.
+//!
+//! Unknown usernames are counted like known ones, so the responses do not tell which users exist.
+//!
+//! ```toml
+//! [throttle]
+//! backoff = 1
+//! max_backoff = 60
+//! lockout_after = 10
+//! lockout = 900
+//! ```
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// The wait after the first failure, in seconds.
+    pub backoff: u64,
+    /// The longest wait before the lockout, in seconds.
+    pub max_backoff: u64,
+    /// The failures in a row that lock out a username or address. 0 never locks out.
+    pub lockout_after: u32,
+    /// How long a lockout lasts, in seconds. Failures older than this are forgotten.
+    pub lockout: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig { backoff: 1, max_backoff: 60, lockout_after: 10, lockout: 15 * 60 }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(String),
+    Addr(IpAddr),
+}
+
+#[derive(Default)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+fn keys(username: &str, addr: Option<IpAddr>) -> Vec<Key> {
+    std::iter::once(Key::User(username.to_string())).chain(addr.map(Key::Addr)).collect()
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle { config, failures: Mutex::new(HashMap::new()) }
+    }
+
+    /// How long `username` or `addr` must wait before trying again, if at all.
+    pub fn check(&self, username: &str, addr: Option<IpAddr>, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock();
+
+        keys(username, addr).iter()
+            .filter_map(|key| failures.get(key))
+            .filter(|f| f.blocked_until > now)
+            .map(|f| f.blocked_until - now)
+            .max()
+    }
+
+    /// Counts a failed login, returning how long to wait before trying again.
+    pub fn failure(&self, username: &str, addr: Option<IpAddr>, now: Instant) -> Duration {
+        let mut failures = self.failures.lock();
+        let lockout = Duration::from_secs(self.config.lockout);
+        let mut wait = Duration::from_secs(0);
+
+        for key in keys(username, addr) {
+            let f = failures.entry(key).or_insert(Failures { count: 0, last: now, blocked_until: now });
+
+            if now.duration_since(f.last) > lockout {
+                f.count = 0;
+            }
+
+            f.count = f.count.saturating_add(1);
+            f.last = now;
+
+            let backoff = if self.config.lockout_after > 0 && f.count >= self.config.lockout_after {
+                lockout
+            }
+            else {
+                let doubled = self.config.backoff.saturating_mul(1u64.checked_shl(f.count - 1).unwrap_or(u64::MAX));
+                Duration::from_secs(doubled.min(self.config.max_backoff))
+            };
+
+            f.blocked_until = f.blocked_until.max(now + backoff);
+            wait = wait.max(f.blocked_until - now);
+        }
+
+        wait
+    }
+
+    /// Forgets the failures of a user that logged in. Those of the address are kept, so one
+    /// account cannot be used to keep guessing the passwords of others.
+    pub fn success(&self, username: &str) {
+        self.failures.lock().remove(&Key::User(username.to_string()));
+    }
+
+    /// Forgets the failures of a user, returning whether there were any.
+    pub fn unlock_user(&self, username: &str) -> bool {
+        self.failures.lock().remove(&Key::User(username.to_string())).is_some()
+    }
+
+    /// Forgets the failures of an address, returning whether there were any.
+    pub fn unlock_addr(&self, addr: IpAddr) -> bool {
+        self.failures.lock().remove(&Key::Addr(addr)).is_some()
+    }
+
+    /// Forgets the failures that no longer block and are older than the lockout.
+    pub fn sweep(&self, now: Instant) {
+        let lockout = Duration::from_secs(self.config.lockout);
+        self.failures.lock().retain(|_, f| f.blocked_until > now || now.duration_since(f.last) <= lockout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_test() {
+        let throttle = Throttle::new(ThrottleConfig { backoff: 1, max_backoff: 4, lockout_after: 5, lockout: 60 });
+        let addr: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+        let other: Option<IpAddr> = Some("10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert_eq!(throttle.check("user", addr, start), None);
+
+        // 1, 2, 4, 4 seconds, then locked out
+        assert_eq!(throttle.failure("user", addr, at(0)), Duration::from_secs(1));
+        assert_eq!(throttle.check("user", other, at(0)), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.check("nobody", addr, at(0)), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.check("nobody", other, at(0)), None);
+        assert_eq!(throttle.check("user", addr, at(1)), None);
+
+        assert_eq!(throttle.failure("user", addr, at(1)), Duration::from_secs(2));
+        assert_eq!(throttle.failure("user", addr, at(3)), Duration::from_secs(4));
+        assert_eq!(throttle.failure("user", addr, at(7)), Duration::from_secs(4));
+        assert_eq!(throttle.failure("user", addr, at(11)), Duration::from_secs(60));
+        assert_eq!(throttle.check("user", other, at(70)), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.check("user", other, at(71)), None);
+
+        // Unlocked by an admin
+        throttle.failure("user", addr, at(71));
+        assert!(throttle.unlock_user("user"));
+        assert!(!throttle.unlock_user("user"));
+        assert_eq!(throttle.check("user", other, at(71)), None);
+        assert!(throttle.check("nobody", addr, at(71)).is_some());
+        assert!(throttle.unlock_addr(addr.unwrap()));
+        assert_eq!(throttle.check("nobody", addr, at(71)), None);
+
+        // Failures are forgotten after the lockout
+        throttle.failure("bob", None, at(100));
+        throttle.success("bob");
+        assert_eq!(throttle.check("bob", None, at(100)), None);
+        assert_eq!(throttle.failure("bob", None, at(200)), Duration::from_secs(1));
+        assert_eq!(throttle.failure("bob", None, at(300)), Duration::from_secs(1));
+
+        throttle.sweep(at(1000));
+        assert!(throttle.failures.lock().is_empty());
+    }
+}
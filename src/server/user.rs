@@ -0,0 +1,368 @@
+//! Users and their SCRAM credentials.
+//!
+//! Passwords are never stored. A user keeps the salt, iteration count, StoredKey and ServerKey
+//! of RFC 5802, which is all the server needs to verify a client proof and sign its reply.
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use parking_lot::RwLock;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::error::*;
+
+use super::scram::ScramHash;
+
+/// The iteration count of new users.
+pub const DEFAULT_ITERATIONS: u32 = 10000;
+
+/// Serializes byte fields as base64 strings.
+mod base64_bytes {
+    use data_encoding::BASE64;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        BASE64.decode(s.as_bytes()).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    #[serde(default)]
+    pub hash: ScramHash,
+    #[serde(with = "base64_bytes")]
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    /// H(ClientKey)
+    #[serde(with = "base64_bytes")]
+    pub stored_key: Vec<u8>,
+    /// HMAC(SaltedPassword, "Server Key")
+    #[serde(with = "base64_bytes")]
+    pub server_key: Vec<u8>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// The length of the salts of new users.
+pub const SALT_LEN: usize = 16;
+
+fn random_salt() -> HaystackResult<Vec<u8>> {
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng::new().map_err(|e| HaystackError::GeneralError(e.to_string()))?.fill_bytes(&mut salt);
+    Ok(salt)
+}
+
+impl User {
+    /// A SHA-256 user with a random salt.
+    pub fn new(username: &str, password: &str, iterations: u32, roles: Vec<String>) -> HaystackResult<Self> {
+        User::new_with_hash(username, password, ScramHash::Sha256, iterations, roles)
+    }
+
+    pub fn new_with_hash(username: &str, password: &str, hash: ScramHash, iterations: u32, roles: Vec<String>) -> HaystackResult<Self> {
+        if username.is_empty() || username.contains([',', '=']) {
+            return Err(HaystackError::GeneralError(format!("Invalid username {:?}", username)));
+        }
+
+        let salt = random_salt()?;
+        let (stored_key, server_key) = hash.keys(password, &salt, iterations)?;
+
+        Ok(User { username: username.to_string(), hash, salt, iterations, stored_key, server_key, roles })
+    }
+
+    /// Replaces the password, with a new salt.
+    pub fn set_password(&mut self, password: &str) -> HaystackResult<()> {
+        let salt = random_salt()?;
+        let (stored_key, server_key) = self.hash.keys(password, &salt, self.iterations)?;
+
+        self.salt = salt;
+        self.stored_key = stored_key;
+        self.server_key = server_key;
+
+        Ok(())
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        match self.hash.keys(password, &self.salt, self.iterations) {
+            Ok((stored_key, _)) => ring::constant_time::verify_slices_are_equal(&stored_key, &self.stored_key).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// Where the server looks up users.
+pub trait UserStore: Send + Sync {
+    fn get(&self, username: &str) -> HaystackResult<Option<User>>;
+
+    /// Adds `user`, replacing any user with the same username.
+    fn put(&self, user: User) -> HaystackResult<()>;
+
+    /// Removes a user, returning whether it existed.
+    fn remove(&self, username: &str) -> HaystackResult<bool>;
+
+    /// The usernames, sorted.
+    fn usernames(&self) -> HaystackResult<Vec<String>>;
+}
+
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: RwLock<BTreeMap<String, User>>,
+}
+
+impl MemoryUserStore {
+    pub fn new(users: Vec<User>) -> Self {
+        MemoryUserStore { users: RwLock::new(users.into_iter().map(|u| (u.username.clone(), u)).collect()) }
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn get(&self, username: &str) -> HaystackResult<Option<User>> {
+        Ok(self.users.read().get(username).cloned())
+    }
+
+    fn put(&self, user: User) -> HaystackResult<()> {
+        self.users.write().insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    fn remove(&self, username: &str) -> HaystackResult<bool> {
+        Ok(self.users.write().remove(username).is_some())
+    }
+
+    fn usernames(&self) -> HaystackResult<Vec<String>> {
+        Ok(self.users.read().keys().cloned().collect())
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct UsersFile {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+// The SHA-256 of the users file, None if there is no file. The content is compared as a
+// rewrite of the same length within the resolution of the modified time would go unnoticed.
+type FileVersion = Option<Vec<u8>>;
+
+/// The content of the users file, None if there is no file.
+fn read_file(path: &Path) -> HaystackResult<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn file_version(content: Option<&str>) -> FileVersion {
+    content.map(|s| digest::digest(&digest::SHA256, s.as_bytes()).as_ref().to_vec())
+}
+
+fn parse_users(path: &Path, content: Option<&str>) -> HaystackResult<BTreeMap<String, User>> {
+    let file: UsersFile = match content {
+        Some(s) => toml::from_str(s)
+            .map_err(|e| HaystackError::GeneralError(format!("Invalid users file {}: {}", path.display(), e)))?,
+        None => UsersFile::default(),
+    };
+
+    Ok(file.users.into_iter().map(|u| (u.username.clone(), u)).collect())
+}
+
+struct LoadedUsers {
+    version: FileVersion,
+    users: BTreeMap<String, User>,
+}
+
+/// Users kept in a TOML file with a `[[users]]` table per user. The file is rewritten on every
+/// change, readable by its owner only as it holds the salts and keys of the users.
+///
+/// The file is read again when it changes, so users added or changed with `haystack_users`
+/// while the server runs can log in without a restart.
+pub struct FileUserStore {
+    path: PathBuf,
+    loaded: RwLock<LoadedUsers>,
+}
+
+impl FileUserStore {
+    /// Opens the store at `path`, which is created on the first change if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> HaystackResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let content = read_file(&path)?;
+        let version = file_version(content.as_deref());
+        let users = parse_users(&path, content.as_deref())?;
+
+        Ok(FileUserStore { path, loaded: RwLock::new(LoadedUsers { version, users }) })
+    }
+
+    /// Reads the file again if it changed since it was last read. A file that no longer parses
+    /// is reported and the users read before are kept.
+    fn refresh(&self) {
+        let content = match read_file(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("Keeping the users read before: {}", e);
+                return;
+            }
+        };
+
+        let version = file_version(content.as_deref());
+
+        if self.loaded.read().version == version {
+            return;
+        }
+
+        let mut loaded = self.loaded.write();
+
+        if loaded.version != version {
+            match parse_users(&self.path, content.as_deref()) {
+                Ok(users) => loaded.users = users,
+                Err(e) => warn!("Keeping the users read before: {}", e),
+            }
+
+            loaded.version = version;
+        }
+    }
+
+    fn save(&self, loaded: &mut LoadedUsers) -> HaystackResult<()> {
+        let file = UsersFile { users: loaded.users.values().cloned().collect() };
+        let s = toml::to_string(&file).map_err(|e| HaystackError::GeneralError(e.to_string()))?;
+
+        // Written aside and renamed so a failed write never leaves a truncated file
+        let tmp = self.path.with_extension("tmp");
+        let _ = std::fs::remove_file(&tmp);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options.open(&tmp)?.write_all(s.as_bytes())?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        loaded.version = file_version(Some(&s));
+
+        Ok(())
+    }
+}
+
+impl UserStore for FileUserStore {
+    fn get(&self, username: &str) -> HaystackResult<Option<User>> {
+        self.refresh();
+        Ok(self.loaded.read().users.get(username).cloned())
+    }
+
+    fn put(&self, user: User) -> HaystackResult<()> {
+        self.refresh();
+
+        let mut loaded = self.loaded.write();
+        loaded.users.insert(user.username.clone(), user);
+        self.save(&mut loaded)
+    }
+
+    fn remove(&self, username: &str) -> HaystackResult<bool> {
+        self.refresh();
+
+        let mut loaded = self.loaded.write();
+
+        if loaded.users.remove(username).is_none() {
+            return Ok(false);
+        }
+
+        self.save(&mut loaded)?;
+        Ok(true)
+    }
+
+    fn usernames(&self) -> HaystackResult<Vec<String>> {
+        self.refresh();
+        Ok(self.loaded.read().users.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_test() {
+        let mut user = User::new("user", "pencil", 4096, vec!["operator".to_string()]).unwrap();
+        assert!(user.verify_password("pencil"));
+        assert!(!user.verify_password("pen"));
+        assert!(user.has_role("operator"));
+        assert!(!user.has_role("admin"));
+
+        let salt = user.salt.clone();
+        user.set_password("crayon").unwrap();
+        assert_ne!(user.salt, salt);
+        assert!(user.verify_password("crayon"));
+        assert!(!user.verify_password("pencil"));
+
+        let user = User::new_with_hash("user", "pencil", ScramHash::Sha512, 4096, vec![]).unwrap();
+        assert_eq!(user.stored_key.len(), 64);
+        assert!(user.verify_password("pencil"));
+
+        assert!(User::new("a,b", "pencil", 4096, vec![]).is_err());
+        assert!(User::new("user", "pencil", 0, vec![]).is_err());
+    }
+
+    #[test]
+    fn file_user_store_test() {
+        let path = std::env::temp_dir().join(format!("haystack-users-{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = FileUserStore::open(&path).unwrap();
+        assert!(store.usernames().unwrap().is_empty());
+
+        store.put(User::new("alice", "pencil", 4096, vec!["admin".to_string()]).unwrap()).unwrap();
+        store.put(User::new("bob", "crayon", 4096, vec![]).unwrap()).unwrap();
+        assert!(store.remove("bob").unwrap());
+        assert!(!store.remove("bob").unwrap());
+
+        let store = FileUserStore::open(&path).unwrap();
+        assert_eq!(store.usernames().unwrap(), vec!["alice".to_string()]);
+
+        let alice = store.get("alice").unwrap().unwrap();
+        assert!(alice.verify_password("pencil"));
+        assert_eq!(alice.roles, vec!["admin".to_string()]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Changes made by another process, ie haystack_users, are picked up
+        let other = FileUserStore::open(&path).unwrap();
+        other.put(User::new("carol", "chalk", 4096, vec!["operator".to_string()]).unwrap()).unwrap();
+        assert!(store.get("carol").unwrap().unwrap().verify_password("chalk"));
+
+        // Including passwords changed twice in quick succession, which keeps the file length
+        let mut carol = other.get("carol").unwrap().unwrap();
+        carol.set_password("pastel").unwrap();
+        other.put(carol.clone()).unwrap();
+        assert!(store.get("carol").unwrap().unwrap().verify_password("pastel"));
+        carol.set_password("chalky").unwrap();
+        other.put(carol).unwrap();
+        assert!(store.get("carol").unwrap().unwrap().verify_password("chalky"));
+
+        assert!(other.remove("carol").unwrap());
+        assert!(store.get("carol").unwrap().is_none());
+        assert_eq!(store.usernames().unwrap(), vec!["alice".to_string()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,522 @@
+//! Watches, the subscriptions behind watchSub, watchUnsub and watchPoll.
+//!
+//! A watch is a set of entity ids a client wants to follow. Each poll returns only the entities
+//! whose tags changed since the previous poll, or all of them on a refresh. A watch lives for its
+//! lease after the last request on it. Expired watches are removed every `Watches::SWEEP_INTERVAL`
+//! by `Watches::spawn_sweeper`, and when any watch op runs.
+//!
+//! A watch belongs to the user that made it, to other users it is unknown.
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::iter;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+use crate::error::*;
+use crate::filter::{ref_id, RefTag, RefTags};
+use crate::hval::HVal;
+use crate::token::*;
+use crate::units;
+
+use super::database::HaystackDatabase;
+use super::read::entities_to_grid;
+use super::auth::Identity;
+use super::format::Format;
+use super::policy::{Policy, PolicyDatabase};
+use super::{bad_request, error_reply, meta_has, meta_value, request_ids};
+
+/// Lease given to a watch when the request doesn't ask for one.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60);
+
+/// The longest lease a watch is given, longer ones are shortened to it.
+pub const MAX_LEASE: Duration = Duration::from_secs(60 * 60);
+
+struct Watch {
+    // username of the user that created the watch, the only one that may use it
+    owner: String,
+    dis: String,
+    lease: Duration,
+    expires: Instant,
+    // ref id -> entity
+    ids: HashMap<String, Token>,
+    // ref id -> tags at the last poll
+    last: HashMap<String, String>,
+}
+
+/// Compares entities by their tags and values, `Tag` equality only compares names.
+fn fingerprint(tags: &[Tag]) -> String {
+    let mut tags: Vec<String> = tags.iter().map(|t| t.to_zinc()).collect();
+    tags.sort();
+    tags.join(" ")
+}
+
+fn key(id: &Token) -> String {
+    ref_id(id).unwrap_or_default().to_string()
+}
+
+/// When a lease given now ends.
+fn lease_end(lease: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(lease.min(MAX_LEASE)).unwrap_or(now)
+}
+
+/// The watches of a server.
+#[derive(Default)]
+pub struct Watches {
+    // Tokens are not Sync so a Mutex rather than a RwLock
+    watches: Mutex<HashMap<String, Watch>>,
+}
+
+impl Watches {
+    /// How often `spawn_sweeper` removes expired watches.
+    pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new() -> Self {
+        Watches::default()
+    }
+
+    /// Sweeps `watches` every `interval` on the tokio runtime, telling `db` about the ids nobody
+    /// watches any more, until `watches` is dropped.
+    pub fn spawn_sweeper<D: HaystackDatabase + 'static>(watches: &Arc<Watches>, db: Arc<D>, interval: Duration) {
+        let watches: Weak<Watches> = Arc::downgrade(watches);
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+            loop {
+                ticks.tick().await;
+
+                match watches.upgrade() {
+                    Some(watches) => sweep(&watches, &*db).await,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.watches.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.lock().is_empty()
+    }
+
+    /// Adds `ids` to the watch `watch_id` of `owner`, or to a new watch of theirs if it is `None`.
+    ///
+    /// Returns the watch id and its lease, or `None` if `owner` has no watch `watch_id`.
+    pub fn sub(&self, owner: &str, watch_id: Option<&str>, dis: &str, lease: Option<Duration>, ids: &[Token]) -> Option<(String, Duration)> {
+        let mut watches = self.watches.lock();
+
+        let watch_id = match watch_id {
+            Some(watch_id) => watch_id.to_string(),
+            None => {
+                let watch_id: String = iter::repeat(())
+                    .map(|()| rand::thread_rng().sample(Alphanumeric))
+                    .take(16)
+                    .collect();
+
+                watches.insert(watch_id.clone(), Watch {
+                    owner: owner.to_string(),
+                    dis: dis.to_string(),
+                    lease: lease.unwrap_or(DEFAULT_LEASE).min(MAX_LEASE),
+                    expires: Instant::now(),
+                    ids: HashMap::new(),
+                    last: HashMap::new(),
+                });
+
+                watch_id
+            }
+        };
+
+        let watch = watches.get_mut(&watch_id).filter(|w| w.owner == owner)?;
+
+        if let Some(lease) = lease {
+            watch.lease = lease.min(MAX_LEASE);
+        }
+
+        watch.expires = lease_end(watch.lease);
+
+        for id in ids {
+            watch.ids.insert(key(id), id.clone());
+        }
+
+        Some((watch_id, watch.lease))
+    }
+
+    /// Removes `ids` from a watch of `owner`, or the whole watch if `close`.
+    ///
+    /// Returns the ids that are no longer in any watch, or `None` if `owner` has no watch
+    /// `watch_id`.
+    pub fn unsub(&self, owner: &str, watch_id: &str, ids: &[Token], close: bool) -> Option<Vec<Token>> {
+        let mut watches = self.watches.lock();
+
+        let removed: Vec<Token> = match watches.get_mut(watch_id).filter(|w| w.owner == owner) {
+            Some(_) if close => watches.remove(watch_id).map(|w| w.ids.into_values().collect()).unwrap_or_default(),
+            Some(watch) => {
+                watch.expires = lease_end(watch.lease);
+
+                ids.iter().filter_map(|id| {
+                    watch.last.remove(&key(id));
+                    watch.ids.remove(&key(id))
+                }).collect()
+            },
+            None => return None,
+        };
+
+        Some(Self::unwatched(&watches, removed))
+    }
+
+    /// The ids of a watch of `owner`, renewing its lease.
+    pub fn ids(&self, owner: &str, watch_id: &str) -> Option<Vec<Token>> {
+        let mut watches = self.watches.lock();
+        let watch = watches.get_mut(watch_id).filter(|w| w.owner == owner)?;
+
+        watch.expires = lease_end(watch.lease);
+
+        Some(watch.ids.values().cloned().collect())
+    }
+
+    pub fn dis(&self, watch_id: &str) -> Option<String> {
+        self.watches.lock().get(watch_id).map(|w| w.dis.clone())
+    }
+
+    /// The `entities` that changed since the last poll of the watch, or all of them if `refresh`.
+    ///
+    /// Entities that are not in the watch are ignored. Returns `None` if there is no watch `watch_id`.
+    pub fn poll(&self, watch_id: &str, entities: &[Option<RefTag>], refresh: bool) -> Option<RefTags> {
+        let mut watches = self.watches.lock();
+        let watch = watches.get_mut(watch_id)?;
+
+        Some(entities.iter().flatten().filter(|(id, tags)| {
+            let id = key(id);
+
+            if !watch.ids.contains_key(&id) {
+                return false;
+            }
+
+            let current = fingerprint(tags);
+            let changed = watch.last.get(&id) != Some(&current);
+
+            watch.last.insert(id, current);
+
+            changed || refresh
+        }).cloned().collect())
+    }
+
+    /// Removes the watches whose lease expired before `now`.
+    ///
+    /// Returns the ids that are no longer in any watch.
+    pub fn sweep(&self, now: Instant) -> Vec<Token> {
+        let mut watches = self.watches.lock();
+
+        let expired: Vec<String> = watches.iter()
+            .filter(|(_, w)| w.expires <= now)
+            .map(|(watch_id, _)| watch_id.clone())
+            .collect();
+
+        let removed: Vec<Token> = expired.iter()
+            .filter_map(|watch_id| watches.remove(watch_id))
+            .flat_map(|w| w.ids.into_values())
+            .collect();
+
+        Self::unwatched(&watches, removed)
+    }
+
+    fn unwatched(watches: &HashMap<String, Watch>, ids: Vec<Token>) -> Vec<Token> {
+        let mut seen = HashSet::new();
+
+        ids.into_iter()
+            .filter(|id| seen.insert(key(id)))
+            .filter(|id| !watches.values().any(|w| w.ids.contains_key(&key(id))))
+            .collect()
+    }
+}
+
+/// Removes expired watches, telling the database about ids nobody watches any more.
+async fn sweep<D: HaystackDatabase>(watches: &Watches, db: &D) {
+    let unwatched = watches.sweep(Instant::now());
+
+    if !unwatched.is_empty() {
+        if let Err(e) = db.watch_unsub(&unwatched).await {
+            debug!("watch_unsub failed: {}", e);
+        }
+    }
+}
+
+/// A lease in seconds, or converted from a time unit such as `1min`, at most `MAX_LEASE`.
+/// `None` for leases that are not finite or not a time.
+fn to_lease(t: &Token) -> Option<Duration> {
+    let secs = match t {
+        Token::Number(n, unit) if unit.is_empty() => n.number,
+        Token::Number(n, unit) => units::convert(n.number, unit, "s")?,
+        _ => return None,
+    };
+
+    if !secs.is_finite() {
+        return None;
+    }
+
+    Duration::try_from_secs_f64(secs.clamp(0.0, MAX_LEASE.as_secs_f64())).ok()
+}
+
+fn watch_grid(watch_id: &str, lease: Option<Duration>, entities: &[Option<RefTag>]) -> Grid {
+    let mut meta = vec![Tag::new_string("watchId", watch_id)];
+
+    if let Some(lease) = lease {
+        meta.push(Tag::new_number("lease", lease.as_secs_f64(), "s"));
+    }
+
+    let mut grid = entities_to_grid(entities);
+    grid.grid_meta = GridMeta::new(Token::Ver("3.0".into()), Some(Tags::new(&meta)));
+    grid
+}
+
+// Request: a grid with a row per id to watch. The metadata has either a watchDis to create a new
+// watch or a watchId to add to an existing one, and an optional lease.
+//
+// ver:"3.0" watchDis:"Dashboard" lease:1min
+// id
+// @someTemp
+//
+// Response: the watchId and lease in the metadata and a row with the current value of each entity.
+pub async fn watch_sub<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    watches: Arc<Watches>,
+    policy: Arc<Policy>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    let db = PolicyDatabase::new(db, policy, &identity);
+
+    sweep(&watches, &db).await;
+
+    let request = match request {
+        Some(g) => g,
+        None => return Ok(bad_request(&format, "Missing request grid")),
+    };
+
+    let (ids, watch_id, dis) = match (request_ids(&request), meta_value(&request, "watchId"), meta_value(&request, "watchDis")) {
+        (Some(ids), Some(Token::EscapedString(watch_id)), _) => (ids, Some(watch_id), String::new()),
+        (Some(ids), None, Some(Token::EscapedString(dis))) => (ids, None, dis),
+        _ => return Ok(bad_request(&format, "Request must have an id column and a watchId or watchDis")),
+    };
+
+    let lease = match meta_value(&request, "lease") {
+        Some(lease) => match to_lease(&lease) {
+            Some(lease) => Some(lease),
+            None => return Ok(bad_request(&format, "lease must be a finite number of seconds or a time")),
+        },
+        None => None,
+    };
+
+    let entities = match db.read_by_ids(&ids).await {
+        Ok(entities) => entities,
+        Err(e) => {
+            debug!("watchSub failed: {}", e);
+            return Ok(error_reply(&format, &e));
+        }
+    };
+
+    // Unknown ids come back as null rows but are not watched
+    let found: Vec<Token> = entities.iter().flatten().map(|(id, _)| id.clone()).collect();
+
+    let (watch_id, lease) = match watches.sub(&identity.username, watch_id.as_deref(), &dis, lease, &found) {
+        Some(sub) => sub,
+        None => return Ok(error_reply(&format, &HaystackError::UnknownRecord(watch_id.unwrap_or_default()))),
+    };
+
+    if let Err(e) = db.watch_sub(&found).await {
+        debug!("watch_sub failed: {}", e);
+    }
+
+    // Record the values the client now has so the next poll only returns changes
+    watches.poll(&watch_id, &entities, true);
+
+    Ok(format.reply(&watch_grid(&watch_id, Some(lease), &entities), http::StatusCode::OK))
+}
+
+// Request: a grid with a row per id to remove, the watchId in the metadata and a close marker to
+// remove the whole watch.
+//
+// ver:"3.0" watchId:"a1b2c3" close
+// id
+//
+// Response: empty grid
+pub async fn watch_unsub<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    watches: Arc<Watches>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    sweep(&watches, &*db).await;
+
+    let request = match request {
+        Some(g) => g,
+        None => return Ok(bad_request(&format, "Missing request grid")),
+    };
+
+    let watch_id = match meta_value(&request, "watchId") {
+        Some(Token::EscapedString(watch_id)) => watch_id,
+        _ => return Ok(bad_request(&format, "Request must have a watchId")),
+    };
+
+    let ids = request_ids(&request).unwrap_or_default();
+    // Other users' watches are unknown
+    let unwatched = match watches.unsub(&identity.username, &watch_id, &ids, meta_has(&request, "close")) {
+        Some(unwatched) => unwatched,
+        None => return Ok(error_reply(&format, &HaystackError::UnknownRecord(watch_id))),
+    };
+
+    if !unwatched.is_empty() {
+        if let Err(e) = db.watch_unsub(&unwatched).await {
+            debug!("watch_unsub failed: {}", e);
+        }
+    }
+
+    Ok(format.reply(&Grid::empty(), http::StatusCode::OK))
+}
+
+// Request: the watchId in the metadata and a refresh marker to get every entity rather than the
+// ones that changed.
+//
+// ver:"3.0" watchId:"a1b2c3" refresh
+// empty
+//
+// Response: the watchId in the metadata and a row per changed entity.
+pub async fn watch_poll<D: HaystackDatabase>(
+    identity: Identity,
+    format: Format,
+    request: Option<Grid>,
+    watches: Arc<Watches>,
+    policy: Arc<Policy>,
+    db: Arc<D>,
+) -> Result<impl warp::Reply, Infallible> {
+
+    let db = PolicyDatabase::new(db, policy, &identity);
+
+    sweep(&watches, &db).await;
+
+    let request = match request {
+        Some(g) => g,
+        None => return Ok(bad_request(&format, "Missing request grid")),
+    };
+
+    let watch_id = match meta_value(&request, "watchId") {
+        Some(Token::EscapedString(watch_id)) => watch_id,
+        _ => return Ok(bad_request(&format, "Request must have a watchId")),
+    };
+
+    let ids = match watches.ids(&identity.username, &watch_id) {
+        Some(ids) => ids,
+        None => return Ok(error_reply(&format, &HaystackError::UnknownRecord(watch_id))),
+    };
+
+    let entities = match db.read_by_ids(&ids).await {
+        Ok(entities) => entities,
+        Err(e) => {
+            debug!("watchPoll failed: {}", e);
+            return Ok(error_reply(&format, &e));
+        }
+    };
+
+    let changed: Vec<Option<RefTag>> = watches.poll(&watch_id, &entities, meta_has(&request, "refresh"))
+        .unwrap_or_default()
+        .into_iter()
+        .map(Some)
+        .collect();
+
+    Ok(format.reply(&watch_grid(&watch_id, None, &changed), http::StatusCode::OK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watch_test() {
+        let watches = Watches::new();
+        let temp = Token::Ref("@temp".to_string(), None);
+        let fan = Token::Ref("@fan".to_string(), None);
+
+        let (watch_id, lease) = watches.sub("alice", None, "Dashboard", None, &[temp.clone(), fan.clone()]).unwrap();
+        assert_eq!(lease, DEFAULT_LEASE);
+        assert_eq!(watches.dis(&watch_id), Some("Dashboard".to_string()));
+        assert!(watches.sub("alice", Some("missing"), "", None, &[]).is_none());
+
+        // Only the user that made a watch can use it
+        assert!(watches.sub("mallory", Some(&watch_id), "", None, &[]).is_none());
+        assert!(watches.ids("mallory", &watch_id).is_none());
+        assert!(watches.unsub("mallory", &watch_id, &[], true).is_none());
+        assert_eq!(watches.len(), 1);
+
+        let entities = vec![
+            Some((temp.clone(), vec![Tag::new_marker("point"), Tag::new_number("curVal", 20.0, "°C")])),
+            Some((fan.clone(), vec![Tag::new_marker("point"), Tag::new_string("curVal", "on")])),
+        ];
+
+        assert_eq!(watches.poll(&watch_id, &entities, false).unwrap().len(), 2);
+        assert_eq!(watches.poll(&watch_id, &entities, false).unwrap().len(), 0);
+        assert_eq!(watches.poll(&watch_id, &entities, true).unwrap().len(), 2);
+
+        let changed = vec![
+            Some((temp.clone(), vec![Tag::new_marker("point"), Tag::new_number("curVal", 21.0, "°C")])),
+            entities[1].clone(),
+        ];
+
+        let polled = watches.poll(&watch_id, &changed, false).unwrap();
+        assert_eq!(polled.len(), 1);
+        assert_eq!(polled[0].0, temp);
+
+        // Still watched by a second watch
+        let (other_id, _) = watches.sub("bob", None, "Other", Some(Duration::from_secs(600)), &[fan.clone()]).unwrap();
+        assert_eq!(watches.unsub("alice", &watch_id, &[fan.clone()], false), Some(vec![]));
+        assert_eq!(watches.ids("alice", &watch_id), Some(vec![temp.clone()]));
+
+        // Expire the first watch but not the second
+        assert_eq!(watches.sweep(Instant::now() + Duration::from_secs(120)), vec![temp]);
+        assert_eq!(watches.len(), 1);
+
+        assert_eq!(watches.unsub("bob", &other_id, &[], true), Some(vec![fan]));
+        assert!(watches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweeper_test() {
+        let watches = Arc::new(Watches::new());
+        let db = Arc::new(crate::server::MemoryDatabase::new(vec![]));
+
+        watches.sub("alice", None, "Short", Some(Duration::from_millis(10)), &[Token::Ref("@temp".to_string(), None)]).unwrap();
+        Watches::spawn_sweeper(&watches, db, Duration::from_millis(20));
+
+        // Removed without any watch op
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(watches.is_empty());
+    }
+
+    #[test]
+    fn lease_test() {
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(2.0), "min".into())), Some(Duration::from_secs(120)));
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(30.0), "".into())), Some(Duration::from_secs(30)));
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(30.0), "m".into())), None);
+
+        // Too long or not finite
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(1e20), "".into())), Some(MAX_LEASE));
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(1e20), "h".into())), Some(MAX_LEASE));
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(f64::INFINITY), "".into())), None);
+        assert_eq!(to_lease(&Token::Inf), None);
+        assert_eq!(to_lease(&Token::Number(ZincNumber::new(f64::NAN), "s".into())), None);
+
+        let watches = Watches::new();
+        let (_, lease) = watches.sub("alice", None, "Forever", Some(Duration::from_secs(u64::MAX)), &[]).unwrap();
+        assert_eq!(lease, MAX_LEASE);
+    }
+}
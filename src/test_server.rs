@@ -1,12 +1,24 @@
-extern crate libproject_haystack_rs;
-
 use libproject_haystack_rs::server;
+use libproject_haystack_rs::server::MemoryDatabase;
+use libproject_haystack_rs::token::*;
 
-use parking_lot::RwLock;
 use std::sync::Arc;
-use std::collections::{HashMap};
 
-use libproject_haystack_rs::error::*;
+// A small site to try the server against
+fn demo_database() -> MemoryDatabase {
+    MemoryDatabase::new(vec![
+        (Token::Ref("@site".to_string(), None),
+            vec![Tag::new_marker("site"), Tag::new_string("dis", "Demo Site"), Tag::new_string("tz", "UTC")]),
+        (Token::Ref("@ahu".to_string(), None),
+            vec![Tag::new_marker("equip"), Tag::new_marker("ahu"), Tag::new_string("dis", "AHU-1"), Tag::new_ref("siteRef", "@site")]),
+        (Token::Ref("@someTemp".to_string(), None),
+            vec![Tag::new_marker("point"), Tag::new_marker("his"), Tag::new_string("dis", "Discharge Temp"), Tag::new_string("tz", "UTC"),
+                 Tag::new_ref("equipRef", "@ahu"), Tag::new_ref("siteRef", "@site")]),
+        (Token::Ref("@fanCmd".to_string(), None),
+            vec![Tag::new_marker("point"), Tag::new_marker("writable"), Tag::new_marker("cmd"), Tag::new_string("dis", "Fan Command"),
+                 Tag::new_string("kind", "Str"), Tag::new_ref("equipRef", "@ahu"), Tag::new_ref("siteRef", "@site")]),
+    ])
+}
 
 #[tokio::main]
 async fn main() {
@@ -15,6 +27,16 @@ async fn main() {
     
     println!("Starting Test Server");
 
-    server::serve().await;
-}
+    let config = server::ServerConfig::load().expect("Invalid server config");
 
+    if config.users.is_some() {
+        server::serve_with(config, Arc::new(demo_database())).await;
+    }
+    else {
+        // Without a users file log in as user / pencil, an operator
+        let user = server::User::new("user", "pencil", server::user::DEFAULT_ITERATIONS, vec![server::policy::OPERATOR.to_string()]).expect("Invalid demo user");
+        let users = Arc::new(server::MemoryUserStore::new(vec![user]));
+
+        server::serve_with_users(config, Arc::new(demo_database()), server::Actions::new(), users).await;
+    }
+}
@@ -0,0 +1,64 @@
+//! Haystack timezone names.
+//!
+//! Haystack names a timezone by the city part of its IANA name, ie `New_York` for
+//! `America/New_York`, with `UTC` and the `Etc/` zones as exceptions.
+use chrono::{DateTime, FixedOffset, Offset, TimeZone};
+use chrono_tz::{Tz, TZ_VARIANTS};
+
+use crate::token::Token;
+
+/// The timezone for a haystack timezone name. Full IANA names are accepted too.
+pub fn find(name: &str) -> Option<Tz> {
+    if let Ok(tz) = name.parse::<Tz>() {
+        return Some(tz);
+    }
+
+    let suffix = format!("/{}", name);
+
+    TZ_VARIANTS.iter().find(|tz| tz.name().ends_with(&suffix)).cloned()
+}
+
+/// The haystack name of a timezone, ie `New_York` for `America/New_York`.
+pub fn name(tz: &Tz) -> &'static str {
+    let name = tz.name();
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+/// `dt` as the local time in `tz`.
+pub fn to_tz(dt: &DateTime<FixedOffset>, tz: &Tz) -> DateTime<FixedOffset> {
+    let local = dt.with_timezone(tz);
+    local.with_timezone(&local.offset().fix())
+}
+
+/// A DateTime token for `dt` in `tz`, ie `2012-10-01T00:00:00-04:00 New_York`.
+pub fn to_token(dt: &DateTime<FixedOffset>, tz: &Tz) -> Token {
+    Token::DateTime(to_tz(dt, tz), Some(name(tz).to_string()))
+}
+
+/// The instant the local time `dt` occurs in `tz`. Times skipped by a daylight saving change
+/// are taken as UTC.
+pub fn from_local(dt: &chrono::NaiveDateTime, tz: &Tz) -> DateTime<FixedOffset> {
+    let local = tz.from_local_datetime(dt).earliest().unwrap_or_else(|| tz.from_utc_datetime(dt));
+    local.with_timezone(&local.offset().fix())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn timezone_test() {
+        assert_eq!(find("New_York"), Some(Tz::America__New_York));
+        assert_eq!(find("UTC"), Some(Tz::UTC));
+        assert_eq!(find("Europe/London"), Some(Tz::Europe__London));
+        assert_eq!(find("Nowhere"), None);
+        assert_eq!(name(&Tz::America__New_York), "New_York");
+
+        let local = from_local(&NaiveDate::from_ymd(2012, 10, 1).and_hms(0, 0, 0), &Tz::America__New_York);
+        assert_eq!(local.to_rfc3339(), "2012-10-01T00:00:00-04:00");
+        assert_eq!(to_tz(&local, &Tz::UTC).to_rfc3339(), "2012-10-01T04:00:00+00:00");
+        assert_eq!(to_token(&local, &Tz::America__New_York).to_string(), "2012-10-01T00:00:00.000-04:00 New_York");
+        assert_eq!(to_token(&local, &Tz::UTC).to_string(), "2012-10-01T04:00:00.000 UTC");
+    }
+}
@@ -17,6 +17,18 @@ fn remove_first(s: &str) -> Option<&str> {
     s.chars().next().map(|c| &s[c.len_utf8()..])
 }
 
+// DateTime: 2010-03-11T23:55:00-05:00 New_York or 2009-11-09T15:39:00Z
+fn datetime_to_zinc(val: &DateTime<FixedOffset>, tz: &Option<String>) -> String {
+    match tz.as_deref() {
+        Some(name) if name != "UTC" => format!("{} {}", val.format("%Y-%m-%dT%H:%M:%S%.3f%:z"), name),
+        // Haystack-rs otherwise returns in Utc
+        _ => {
+            let utc: DateTime<Utc> = val.with_timezone(&Utc);
+            format!("{}", utc.format("%Y-%m-%dT%H:%M:%S%.3f %Z"))
+        }
+    }
+}
+
 /// An error reported by the parser.
 #[derive(Debug, Clone)]
 pub enum TokenParseError {
@@ -137,13 +149,18 @@ pub enum Token {
 
     Ref(String, Option<String>),
 
+    /// A def name, ie ^elec-meter. The leading caret is not stored.
+    Symbol(String),
+
     EscapedString(String),
 
     Date(NaiveDate),
 
     Time(NaiveTime),
 
-    DateTime(DateTime<FixedOffset>),
+    /// A timestamp and the haystack name of its timezone, ie `New_York`. Without a name it is
+    /// encoded in UTC.
+    DateTime(DateTime<FixedOffset>, Option<String>),
 
     Uri(String),
 
@@ -168,6 +185,26 @@ impl Token {
             _ => None
         }
     }
+
+    /// The haystack kind name of a scalar value, as used by the `kind` tag of points.
+    pub fn kind(&self) -> Option<&'static str> {
+
+        match &self {
+            Token::Marker => Some("Marker"),
+            Token::Remove => Some("Remove"),
+            Token::NA => Some("NA"),
+            Token::Bool(_) => Some("Bool"),
+            Token::Number(_, _) | Token::Inf | Token::InfNeg | Token::NaN => Some("Number"),
+            Token::Ref(_, _) => Some("Ref"),
+            Token::Symbol(_) => Some("Symbol"),
+            Token::EscapedString(_) => Some("Str"),
+            Token::Date(_) => Some("Date"),
+            Token::Time(_) => Some("Time"),
+            Token::DateTime(..) => Some("DateTime"),
+            Token::Uri(_) => Some("Uri"),
+            _ => None
+        }
+    }
 }
 
 // impl Hash for Token {
@@ -234,15 +271,14 @@ impl fmt::Display for Token {
                 }
             },
 
+            Token::Symbol(val) => write!(f, "^{}", val),
+
             Token::EscapedString(val) => write!(f, "{}", val),
         
             Token::Date(val) => write!(f, "{}", val.format("%Y-%m-%d")),
             Token::Time(val) => write!(f, "{}", val.format("%H:%M:%S")),
 
-            Token::DateTime(val) => {
-                let utc: DateTime<Utc> = val.with_timezone(&Utc);
-                write!(f, "{}", utc.format("%Y-%m-%dT%H:%M:%S%.3f %Z").to_string())
-            }
+            Token::DateTime(val, tz) => write!(f, "{}", datetime_to_zinc(val, tz)),
             
             Token::Uri(val) => write!(f, "{}", val),
             Token::Ver(val) => write!(f, "{}", val),
@@ -292,19 +328,15 @@ impl HVal for Token {
                 }
             },
 
+            Token::Symbol(val) => format!("^{}", val),
+
             Token::EscapedString(val) => format!("\"{}\"", val.escape_debug()),
         
             Token::Date(val) => format!("{}", val.format("%Y-%m-%d")),
 
             Token::Time(val) => format!("{}", val.format("%H:%M:%S")),
 
-            Token::DateTime(val) => {
-
-                // DateTime: 2010-03-11T23:55:00-05:00 New_York or 2009-11-09T15:39:00Z
-                // Haystack-rs always returns in Utc
-                let utc: DateTime<Utc> = val.with_timezone(&Utc);
-                return format!("{}", utc.format("%Y-%m-%dT%H:%M:%S%.3f %Z"))
-            }
+            Token::DateTime(val, tz) => datetime_to_zinc(val, tz),
             
             Token::Uri(val) => format!("`{}`", val),
             Token::Ver(val) => format!("ver:\"{}\"", val),
@@ -314,7 +346,7 @@ impl HVal for Token {
     }
 
     fn to_json(&self) -> String  {
-        return  "".to_string();
+        crate::json::to_json(self).to_string()
     }
 }
 
@@ -394,7 +426,7 @@ impl HVal for Val {
     }
  
     fn to_json(&self) -> String  {
-        return  "".to_string();
+        crate::json::to_json(self).to_string()
     }
 }
 
@@ -602,7 +634,11 @@ impl Tag {
             return false;
         }
 
-        let v = self.value.clone().unwrap();
+        // Markers have no value
+        let v = match self.value.clone() {
+            Some(v) => v,
+            None => return false,
+        };
 
         let token_option = v.cast_to_type::<Token>();
 
@@ -733,6 +769,10 @@ impl Tags {
         self.tags.len()
     }
 
+    pub fn iter(&self) -> std::slice::Iter<'_, Tag> {
+        self.tags.iter()
+    }
+
     pub fn get(&self, id: &str) -> Option<&Tag> {
         for t in self.tags.iter() {
             if t.get_id() == id {
@@ -843,6 +883,30 @@ impl Dict {
     pub fn new_from_tags(tags: &Tags) -> Self {
         Dict::new(&tags.tags)
     }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// True if the dict has tag `id`, including markers.
+    pub fn has(&self, id: &str) -> bool {
+        self.map.contains_key(id)
+    }
+
+    pub fn get_value<T>(&self, id: &str) -> Option<T>
+        where T: HVal + Clone {
+
+        self.map.get(id)?.as_ref()?.get_value::<T>()
+    }
+
+    /// The tags by name in name order. Markers have no value.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Option<&Val>)> {
+        self.map.iter().map(|(name, tag)| (name, tag.as_ref().and_then(|t| t.value.as_ref())))
+    }
 }
 
 impl fmt::Debug for Dict {
@@ -884,7 +948,7 @@ impl HVal for Dict {
     }
  
     fn to_json(&self) -> String  {
-        return  "".to_string();
+        crate::json::to_json(self).to_string()
     }
 }
 
@@ -903,6 +967,10 @@ impl List {
         List::new(tokens.iter().map(|t| Val::new(Box::new(t.clone()))).collect())
     }
 
+    pub fn iter(&self) -> std::slice::Iter<'_, Val> {
+        self.vals.iter()
+    }
+
     /// Fails in types in vals are not all the same
     pub fn cast_to_type_ref<T>(&self) -> Option<Vec<&T>>
         where T: HVal {
@@ -964,7 +1032,7 @@ impl HVal for List {
     }
  
     fn to_json(&self) -> String  {
-        return  "".to_string();
+        crate::json::to_json(self).to_string()
     }
 }
 
@@ -1044,8 +1112,9 @@ impl HVal for Col {
     }
 
     fn to_zinc(&self) -> String  {
-        if self.tags.is_some() {
-            format!("{}:{}", self.id.to_zinc(), self.tags.clone().unwrap().to_zinc())
+        // Column meta follows the name after a space, ie v0 id:@someTemp
+        if self.tags.as_ref().is_some_and(|t| t.len() > 0) {
+            format!("{} {}", self.id.to_zinc(), self.tags.clone().unwrap().to_zinc())
         }
         else {
             format!("{}", self.id.to_zinc())
@@ -1398,7 +1467,7 @@ impl HVal for GridMeta {
     }
 
     fn to_zinc(&self) -> String  {
-        if self.metadata.is_some() {
+        if self.metadata.as_ref().is_some_and(|m| m.len() > 0) {
             format!("{} {}", self.version.to_zinc(), self.metadata.clone().unwrap().to_zinc())
         }
         else {
@@ -1466,7 +1535,7 @@ impl HVal for Grid {
     }
  
     fn to_json(&self) -> String  {
-        return  "".to_string();
+        crate::json::to_json(self).to_string()
     }
 }
 
@@ -0,0 +1,155 @@
+//! Conversion between haystack units.
+//!
+//! Each unit belongs to a quantity and is defined by how it maps onto that quantity's base unit,
+//! `base = value * scale + offset`. Only units of the same quantity can be converted, ie `°F` to
+//! `°C` but not `°F` to `kW`.
+
+pub struct Unit {
+    pub quantity: &'static str,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+const fn linear(quantity: &'static str, scale: f64) -> Unit {
+    Unit { quantity, scale, offset: 0.0 }
+}
+
+// (names, unit). Names are the haystack symbol followed by any aliases.
+static UNITS: &[(&[&str], Unit)] = &[
+    // temperature, base kelvin
+    (&["°C", "celsius", "degC"], Unit { quantity: "temperature", scale: 1.0, offset: 273.15 }),
+    (&["°F", "fahrenheit", "degF"], Unit { quantity: "temperature", scale: 5.0 / 9.0, offset: 273.15 - 32.0 * 5.0 / 9.0 }),
+    (&["K", "kelvin"], linear("temperature", 1.0)),
+
+    // temperature differential, base kelvin
+    (&["Δ°C", "celsius_degrees"], linear("temperature differential", 1.0)),
+    (&["Δ°F", "fahrenheit_degrees"], linear("temperature differential", 5.0 / 9.0)),
+
+    // power, base watt
+    (&["W", "watt"], linear("power", 1.0)),
+    (&["kW", "kilowatt"], linear("power", 1000.0)),
+    (&["MW", "megawatt"], linear("power", 1000000.0)),
+    (&["BTU/h", "btus_per_hour"], linear("power", 0.29307107)),
+    (&["hp", "horsepower"], linear("power", 745.699872)),
+    (&["tonref", "tons_refrigeration"], linear("power", 3516.852842)),
+
+    // energy, base joule
+    (&["J", "joule"], linear("energy", 1.0)),
+    (&["kJ", "kilojoule"], linear("energy", 1000.0)),
+    (&["Wh", "watt_hour"], linear("energy", 3600.0)),
+    (&["kWh", "kilowatt_hour"], linear("energy", 3600000.0)),
+    (&["MWh", "megawatt_hour"], linear("energy", 3600000000.0)),
+    (&["BTU", "btu"], linear("energy", 1055.055853)),
+    (&["therm"], linear("energy", 105480400.0)),
+
+    // length, base metre
+    (&["m", "meter"], linear("length", 1.0)),
+    (&["km", "kilometer"], linear("length", 1000.0)),
+    (&["cm", "centimeter"], linear("length", 0.01)),
+    (&["mm", "millimeter"], linear("length", 0.001)),
+    (&["ft", "foot"], linear("length", 0.3048)),
+    (&["in", "inch"], linear("length", 0.0254)),
+    (&["mi", "mile"], linear("length", 1609.344)),
+
+    // area, base square metre
+    (&["m²", "square_meter"], linear("area", 1.0)),
+    (&["ft²", "square_foot"], linear("area", 0.09290304)),
+
+    // volume, base cubic metre
+    (&["m³", "cubic_meter"], linear("volume", 1.0)),
+    (&["L", "liter"], linear("volume", 0.001)),
+    (&["gal", "gallon"], linear("volume", 0.003785411784)),
+    (&["ft³", "cubic_foot"], linear("volume", 0.028316846592)),
+
+    // volumetric flow, base cubic metre per second
+    (&["m³/s", "cubic_meters_per_second"], linear("volumetric flow", 1.0)),
+    (&["m³/h", "cubic_meters_per_hour"], linear("volumetric flow", 1.0 / 3600.0)),
+    (&["L/s", "liters_per_second"], linear("volumetric flow", 0.001)),
+    (&["L/min", "liters_per_minute"], linear("volumetric flow", 0.001 / 60.0)),
+    (&["cfm", "cubic_feet_per_minute"], linear("volumetric flow", 0.028316846592 / 60.0)),
+    (&["gal/min", "gallons_per_minute"], linear("volumetric flow", 0.003785411784 / 60.0)),
+
+    // pressure, base pascal
+    (&["Pa", "pascal"], linear("pressure", 1.0)),
+    (&["kPa", "kilopascal"], linear("pressure", 1000.0)),
+    (&["bar"], linear("pressure", 100000.0)),
+    (&["mbar", "millibar"], linear("pressure", 100.0)),
+    (&["psi", "pounds_per_square_inch"], linear("pressure", 6894.757293)),
+    (&["inH₂O", "inches_of_water"], linear("pressure", 249.082)),
+
+    // time, base second
+    (&["ms", "millisecond"], linear("time", 0.001)),
+    (&["s", "sec", "second"], linear("time", 1.0)),
+    (&["min", "minute"], linear("time", 60.0)),
+    (&["h", "hr", "hour"], linear("time", 3600.0)),
+    (&["day"], linear("time", 86400.0)),
+    (&["wk", "week"], linear("time", 604800.0)),
+
+    // mass, base kilogram
+    (&["kg", "Kg", "kilogram"], linear("mass", 1.0)),
+    (&["g", "gram"], linear("mass", 0.001)),
+    (&["lb", "pound"], linear("mass", 0.45359237)),
+
+    // dimensionless ratio, base fraction
+    (&["%", "percent"], linear("dimensionless", 0.01)),
+];
+
+/// Looks up a unit by its symbol or name.
+pub fn find(name: &str) -> Option<&'static Unit> {
+    UNITS.iter().find(|(names, _)| names.contains(&name)).map(|(_, unit)| unit)
+}
+
+/// Returns true if a value in unit `a` can be converted to unit `b`.
+pub fn is_compatible(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+
+    match (find(a), find(b)) {
+        (Some(a), Some(b)) => a.quantity == b.quantity,
+        _ => false,
+    }
+}
+
+/// Converts `value` from unit `from` to unit `to`. Returns `None` if the units are unknown or
+/// measure different quantities.
+pub fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+    if from == to {
+        return Some(value);
+    }
+
+    let (f, t) = (find(from)?, find(to)?);
+
+    if f.quantity != t.quantity {
+        return None;
+    }
+
+    let base = value * f.scale + f.offset;
+    Some((base - t.offset) / t.scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(a: Option<f64>, b: f64) -> bool {
+        (a.unwrap() - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn convert_test() {
+        assert!(approx(convert(212.0, "°F", "°C"), 100.0));
+        assert!(approx(convert(21.0, "°C", "°F"), 69.8));
+        assert!(approx(convert(0.0, "°C", "K"), 273.15));
+        assert!(approx(convert(2.5, "kW", "W"), 2500.0));
+        assert!(approx(convert(1.0, "kWh", "kJ"), 3600.0));
+        assert!(approx(convert(12.0, "in", "ft"), 1.0));
+        assert!(approx(convert(50.0, "%", "%"), 50.0));
+
+        assert_eq!(convert(70.0, "°F", "kW"), None);
+        assert_eq!(convert(70.0, "°F", "furlong"), None);
+
+        assert!(is_compatible("fahrenheit", "°C"));
+        assert!(!is_compatible("m", "s"));
+    }
+}
@@ -1,17 +1,19 @@
 //! Tokenizer that converts a zinc string form into a series of `Token`s.
 use nom::{
     branch::alt,
-    bytes::complete::{is_a, tag},
-    character::complete::{alphanumeric1, char, digit1, multispace0, multispace1, newline, one_of, space0, space1},
-    combinator::{complete, map, opt, peek, recognize},
+    bytes::complete::{is_a, tag, take_while, take_while1},
+    character::complete::{char, digit1, multispace0, multispace1, newline, one_of, space0, space1},
+    combinator::{complete, map, map_opt, opt, peek, recognize},
     error::ErrorKind,
     multi::{many1, separated_list},
     sequence::{delimited, preceded, separated_pair, terminated, tuple}, IResult,
 };
 
-use chrono::{Date, DateTime, Datelike, FixedOffset, NaiveTime, NaiveDateTime, TimeZone, Utc};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 
 use crate::hval::HVal;
+use crate::timezone;
 use crate::token::*;
 
 /// let parser = delimited(tag("abc"), tag("|"), tag("efg"));
@@ -158,12 +160,12 @@ pub fn quoted_string<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorK
     })(i)
 }
 
-fn unicode_alpha0(i: &str) -> nom::IResult<&str, &str> {
-    nom_unicode::complete::alpha0(i)
+fn uri_chars<'a>(i: &'a str) -> IResult<&'a str, &'a str, (&'a str, ErrorKind)> {
+    take_while(|c: char| c != '`')(i)
 }
 
 pub fn uri<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
-    let qs = preceded(tag("`"), unicode_alpha0);
+    let qs = preceded(tag("`"), uri_chars);
     map(terminated(qs, tag("`")), |s: &str| {
         Token::Uri(s.to_string())
     })(i)
@@ -190,8 +192,8 @@ pub fn date_s<'a>(i: &'a str) -> IResult<&'a str, &'a str, (&'a str, ErrorKind)>
 }
 
 pub fn date<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
-    map(date_s, |s: &str| {
-        Token::Date(dtparse::parse(s).unwrap().0.date())
+    map_opt(date_s, |s: &str| {
+        dtparse::parse(s).ok().map(|dt| Token::Date(dt.0.date()))
     })(i)
 }
 
@@ -208,20 +210,6 @@ fn date_range_s<'a>(i: &'a str) -> IResult<&'a str, (&'a str, &'a str), (&'a str
 // .from_local_datetime(&tmp.0)
 // .unwrap();
 
-fn naive_datetime_to_fixed_offset(dt: NaiveDateTime) -> DateTime::<FixedOffset> {
-    let u = DateTime::<Utc>::from_utc(dt, Utc);
-    DateTime::<FixedOffset>::from(u)
-}
-
-fn date_range<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(date_range_s, |t: (&str, &str)| {
-        (
-            Token::DateTime(naive_datetime_to_fixed_offset(dtparse::parse(t.0).unwrap().0.date().and_hms(0,0,0))),
-            Token::DateTime(naive_datetime_to_fixed_offset(dtparse::parse(t.1).unwrap().0.date().and_hms(0,0,0)))
-        )
-    })(i)
-}
-
 fn hours_minutes_s<'a>(i: &'a str) -> IResult<&'a str, &'a str, (&'a str, ErrorKind)> {
     recognize(tuple((digit1, char(':'), digit1)))(i)
 }
@@ -318,7 +306,7 @@ fn str_to_datetime_token(s: &str) -> Token {
         dt = tz_offset.from_local_datetime(&tmp.0).unwrap();
     }
 
-    Token::DateTime(dt)
+    Token::DateTime(dt, vec.get(1).map(|tz| tz.to_string()))
 }
 
 fn datetime<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
@@ -333,105 +321,56 @@ fn datetime_range_s<'a>(i: &'a str) -> IResult<&'a str, (&'a str, &'a str), (&'a
     separated_pair(datetime_s, char(','), datetime_s)(i)
 }
 
-fn datetime_range<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(datetime_range_s, |t: (&str, &str)| {
-        (str_to_datetime_token(t.0), str_to_datetime_token(t.1))
-    }
-    )(i)
-}
-
-fn utc_date_floor(dt: Date<Utc>) -> DateTime::<FixedOffset> {
-    let midnight = dt.and_hms(0,0,0);
-    DateTime::<FixedOffset>::from(midnight)
-}
-
-fn range_lastfiveminutes<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(tag("lastfiveminutes"), |_: &str| {
-        (
-            Token::DateTime(DateTime::<FixedOffset>::from(Utc::now() - chrono::Duration::minutes(5))),
-            Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))
-        )
-        }
-    )(i)
-}
-
-fn range_lasthour<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(tag("lasthour"), |_: &str| {
-        (
-            Token::DateTime(DateTime::<FixedOffset>::from(Utc::now() - chrono::Duration::minutes(60))),
-            Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))
-        )
-        }
-    )(i)
-}
-
-fn range_today<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(tag("today"), |_: &str| (Token::DateTime(utc_date_floor(Utc::today())), Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))))(i)
-}
+/// A datetime in a range. Datetimes without an offset are local times in `tz`.
+fn range_datetime(s: &str, tz: &Tz) -> Option<DateTime<FixedOffset>> {
+    // Split off the tz name, the offset is enough
+    let s = s.split(' ').next()?;
+    let (naive, offset) = dtparse::parse(s).ok()?;
 
-fn range_yesterday<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(tag("yesterday"), |_: &str| {
-        (
-            Token::DateTime(utc_date_floor(Utc::today() - chrono::Duration::days(1))),
-            Token::DateTime(utc_date_floor(Utc::today()))
-        )
-        }
-    )(i)
-}
-
-fn range_thisweek<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    
-    map(tag("thisweek"), |_: &str| {
-            let weekday: chrono::Weekday = Utc::today().weekday();
-            let number_of_days_from_sunday: u32 = weekday.num_days_from_sunday();
-        
-            (Token::DateTime(utc_date_floor(Utc::today() - chrono::Duration::days(number_of_days_from_sunday as i64))),
-             Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))
-            )
-        }
-    )(i)
-}
-
-fn range_thismonth<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(tag("thismonth"), |_: &str| {
-        (
-            Token::DateTime(utc_date_floor(Utc::today().with_day(1).unwrap())),
-            Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))
-        )
+    match offset {
+        Some(offset) => Some(timezone::to_tz(&offset.from_local_datetime(&naive).single()?, tz)),
+        None => Some(timezone::from_local(&naive, tz)),
     }
-    )(i)
 }
 
-fn range_thisyear<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    map(tag("thisyear"), |_: &str| {
-        (Token::DateTime(utc_date_floor(Utc::today().with_day(1).unwrap().with_month(1).unwrap())),
-         Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))
-        )
-    }
-    )(i)
+fn range_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
 }
 
+/// Parses a hisRead range in the timezone `tz` with `now` as the current time.
+///
+/// Date ranges run from midnight of the first date to midnight of the day after the last date,
+/// and a single datetime runs until `now`.
+pub fn date_range_to_token_at<'a>(i: &'a str, tz: &Tz, now: DateTime<Utc>) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
+    let now = timezone::to_tz(&DateTime::<FixedOffset>::from(now), tz);
+    let today = now.date().naive_local();
+    let midnight = |d: NaiveDate| timezone::from_local(&d.and_hms(0, 0, 0), tz);
+
+    let (rest, (start, end)) = alt((
+        map(tag("today"), |_| (midnight(today), midnight(today.succ()))),
+        map(tag("yesterday"), |_| (midnight(today.pred()), midnight(today))),
+        map(tag("thisweek"), |_| (midnight(today - chrono::Duration::days(today.weekday().num_days_from_sunday() as i64)), now)),
+        map(tag("thismonth"), |_| (midnight(today.with_day(1).unwrap()), now)),
+        map(tag("thisyear"), |_| (midnight(today.with_ordinal(1).unwrap()), now)),
+        map(tag("lastfiveminutes"), |_| (now - chrono::Duration::minutes(5), now)),
+        map(tag("lasthour"), |_| (now - chrono::Duration::minutes(60), now)),
+        map_opt(datetime_range_s, |(a, b)| Some((range_datetime(a, tz)?, range_datetime(b, tz)?))),
+        map_opt(datetime_s, |a| Some((range_datetime(a, tz)?, now))),
+        map_opt(date_range_s, |(a, b)| Some((midnight(range_date(a)?), midnight(range_date(b)?.succ())))),
+        map_opt(date_s, |a| range_date(a).map(|d| (midnight(d), midnight(d.succ())))),
+    ))(i)?;
+
+    Ok((rest, (timezone::to_token(&start, tz), timezone::to_token(&end, tz))))
+}
+
+/// Parses a hisRead range in the timezone `tz`.
+pub fn date_range_to_token_in_tz<'a>(i: &'a str, tz: &Tz) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
+    date_range_to_token_at(i, tz, Utc::now())
+}
+
+/// Parses a hisRead range in UTC.
 pub fn date_range_to_token<'a>(i: &'a str) -> IResult<&'a str, (Token, Token), (&'a str, ErrorKind)> {
-    alt((
-        range_today,
-        range_yesterday,
-        range_thisweek,
-        range_thismonth,
-        range_thisyear,
-        range_lastfiveminutes,
-        range_lasthour,
-        datetime_range,
-        map(datetime_s, |s: &str| (str_to_datetime_token(s), Token::DateTime(DateTime::<FixedOffset>::from(Utc::now())))),
-        date_range,
-        map(date_s, |s: &str| {
-            
-            (
-                Token::DateTime(naive_datetime_to_fixed_offset(dtparse::parse(s).unwrap().0.date().and_hms(0,0,0))),
-                Token::DateTime(DateTime::<FixedOffset>::from(Utc::now()))
-            )
-            }
-        ),
-    ))(i)
+    date_range_to_token_in_tz(i, &Tz::UTC)
 }
 
 pub fn ident<'a>(i: &'a str) -> IResult<&'a str, &'a str, (&'a str, ErrorKind)> {
@@ -497,8 +436,9 @@ fn number<'a>(i: &'a str) -> IResult<&'a str, f64, (&'a str, ErrorKind)> {
     )(i)
 }
 
+// Unit chars are alpha, %, _, /, $ and any unicode char such as °
 fn units<'a>(i: &'a str) -> IResult<&'a str, &'a str, (&'a str, ErrorKind)> {
-    alphanumeric1(i)
+    take_while1(|c: char| c.is_alphanumeric() || "%_/$".contains(c) || !c.is_ascii())(i)
 }
 
 pub fn number_with_unit<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
@@ -533,6 +473,12 @@ pub fn zinc_ref<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)>
     )(i)
 }
 
+// <symbol>      := "^" <refChar>*
+// ie ^elec-meter or ^lib:phIoT
+pub fn symbol<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
+    map(preceded(tag("^"), ref_char), |s: &str| Token::Symbol(s.to_string()))(i)
+}
+
 fn ver<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
     map(
         separated_pair(tag("ver"), char(':'), quoted_string_s),
@@ -543,6 +489,7 @@ fn ver<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
 pub fn token<'a>(i: &'a str) -> IResult<&'a str, Token, (&'a str, ErrorKind)> {
     alt((
         zinc_ref,
+        symbol,
         quoted_string,
         uri,
         datetime,
@@ -629,7 +576,7 @@ fn dict<'a>(i: &'a str) -> IResult<&'a str, Val, (&'a str, ErrorKind)> {
 }
 
 fn list_of_vals<'a>(i: &'a str) -> IResult<&'a str, Vec<Val>, (&'a str, ErrorKind)> {
-    terminated(separated_list(spacey(tag(",")), scalar), opt(tag(",")))(i)
+    terminated(separated_list(spacey(tag(",")), val), opt(tag(",")))(i)
 }
 
 fn list<'a>(i: &'a str) -> IResult<&'a str, Val, (&'a str, ErrorKind)> {
@@ -679,9 +626,15 @@ fn cell<'a>(i: &'a str) -> IResult<&'a str, Val, (&'a str, ErrorKind)> {
 fn row<'a>(i: &'a str) -> IResult<&'a str, Row, (&'a str, ErrorKind)> {
     //separated_list(spacey(char(',')), cell)(i)
 
-    map(separated_list(spacey(char(',')), cell), |v: Vec<Val>| {
+    map(tuple((separated_list(spacey(char(',')), cell), opt(spacey(char(','))))), |t: (Vec<Val>, Option<char>)| {
         // let tmp: Vec<Box<Token>> = v.into_iter().map(|x| Box::new(x)).collect();
         // Token::Row(tmp)
+        let mut v = t.0;
+
+        // A trailing comma means the last cell is empty
+        if t.1.is_some() {
+            v.push(Val::new(Box::new(Token::Null) as Box<dyn HVal>));
+        }
 
         Row::new(v)
     })(i)
@@ -842,14 +795,33 @@ mod tests {
        println!("{:?}", time_s("11:30:00"));
        println!("{:?}", time_with_subseconds_s("11:30:00.677428186"));
        println!("{:?}", datetime_s("2020-09-02T11:30:00+00:00"));
-       println!("{:?}", datetime_range("2020-09-02T11:30:00+00:00,2020-09-02T12:30:00+00:00"));
        println!("{:?}", date_range_to_token("2020-09-02T11:30:00+00:00"));
        println!("{:?}", datetime_range_s("2021-03-15T00:00:00,2021-03-15T01:30:59"));
-       println!("{:?}", datetime_range("2021-03-15T00:00:00,2021-03-15T01:30:59"));
        println!("{:?}", date_range_to_token("2020-09-02T11:30:00,2020-09-02T12:30:00"));
 
     }
 
+    #[test]
+    fn date_range_in_tz_test() {
+        let tz = Tz::America__New_York;
+        let now = Utc.ymd(2012, 10, 2).and_hms(12, 0, 0);
+
+        let range = |s: &str| match date_range_to_token_at(s, &tz, now) {
+            Ok(("", (Token::DateTime(start, _), Token::DateTime(end, _)))) => (start.to_rfc3339(), end.to_rfc3339()),
+            other => panic!("{:?}", other),
+        };
+
+        assert_eq!(range("2012-10-01"), ("2012-10-01T00:00:00-04:00".to_string(), "2012-10-02T00:00:00-04:00".to_string()));
+        assert_eq!(range("2012-10-01,2012-10-02"), ("2012-10-01T00:00:00-04:00".to_string(), "2012-10-03T00:00:00-04:00".to_string()));
+        assert_eq!(range("yesterday"), ("2012-10-01T00:00:00-04:00".to_string(), "2012-10-02T00:00:00-04:00".to_string()));
+        assert_eq!(range("today").1, "2012-10-03T00:00:00-04:00");
+        assert_eq!(range("2012-10-01T12:00:00Z,2012-10-01T13:00:00").0, "2012-10-01T08:00:00-04:00");
+        assert_eq!(range("2012-10-01T12:00:00Z,2012-10-01T13:00:00").1, "2012-10-01T13:00:00-04:00");
+        assert_eq!(range("2012-10-02T07:00:00-04:00 New_York"), ("2012-10-02T07:00:00-04:00".to_string(), "2012-10-02T08:00:00-04:00".to_string()));
+
+        assert!(date_range_to_token_at("2012-13-45", &tz, now).is_err());
+    }
+
     #[test]
     fn date_test() {
         use super::*;
@@ -925,14 +897,14 @@ mod tests {
 
         assert_eq!(
             datetime("2012-09-29T14:56:18.277Z"),
-            Ok(("", Token::DateTime(dt)))
+            Ok(("", Token::DateTime(dt, None)))
         );
 
         dt = DateTime::parse_from_rfc3339("2011-06-07T09:51:27-04:00").unwrap();
 
         assert_eq!(
             datetime("2011-06-07T09:51:27-04:00 New_York"),
-            Ok(("", Token::DateTime(dt)))
+            Ok(("", Token::DateTime(dt, Some("New_York".to_string()))))
         );
 
         assert_eq!(
@@ -988,6 +960,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn nested_list_test() {
+        assert_nom_fn_eq!(
+            list("[[1,2],[]]"),
+            r#"Ok(("", List([List([Number(ZincNumber { number: 1.0 }, ""), Number(ZincNumber { number: 2.0 }, "")]), List([])])))"#
+        );
+
+        assert_nom_fn_eq!(
+            list("[1, [T]]"),
+            r#"Ok(("", List([Number(ZincNumber { number: 1.0 }, ""), List([Bool(true)])])))"#
+        );
+
+        assert_nom_fn_is_ok!(list("[1, {site}, <<ver:\"3.0\"\na\n1\n>>]"));
+    }
+
     #[test]
     fn tags_test() {
         use super::*;
@@ -1131,6 +1118,17 @@ mod tests {
             Ok(("", row![number!(1.0), Token::Null, number!(2.0), Token::Null, number!(5.0),
                          Token::EscapedString("projName".to_string()), number!(8.0), Token::Null, number!(9.0)]))
         );
+
+        // A trailing comma leaves the last cell empty
+        assert_eq!(
+            row("1,2,"),
+            Ok(("", row![number!(1.0), number!(2.0), Token::Null]))
+        );
+
+        assert_eq!(
+            row("1 , "),
+            Ok(("", row![number!(1.0), Token::Null]))
+        );
     }
 
     #[test]
@@ -1475,6 +1473,16 @@ mod tests {
             Ok(("", Token::Number(ZincNumber::new(-5.4e-45f64), "Kg".into())))
         );
 
+        assert_eq!(
+            zinc_number("72.5°F"),
+            Ok(("", Token::Number(ZincNumber::new(72.5f64), "°F".into())))
+        );
+
+        assert_eq!(
+            zinc_number("60m³/h"),
+            Ok(("", Token::Number(ZincNumber::new(60f64), "m³/h".into())))
+        );
+
         assert_eq!(null("N"), Ok(("", Token::Null)));
 
         assert_ne!(null("n"), Ok(("", Token::Null)));
@@ -1503,6 +1511,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn uri_chars_test() {
+        // Anything but a backtick, not just letters
+        assert_eq!(
+            uri("`http://foo.com/f?q=1&r=2`"),
+            Ok(("", Token::Uri("http://foo.com/f?q=1&r=2".into())))
+        );
+
+        assert_eq!(uri("``"), Ok(("", Token::Uri("".into()))));
+        assert!(uri("`unterminated").is_err());
+    }
+
     #[test]
     fn write_dict() {
         let now: DateTime<FixedOffset> = DateTime::<FixedOffset>::from(Utc::now());
@@ -1514,7 +1534,7 @@ mod tests {
             ),
             Tag::new_from_token(
                 Token::EscapedString("serverTime".into()),
-                Token::DateTime(now),
+                Token::DateTime(now, None),
             ),
             Tag::new_from_token(
                 Token::EscapedString("tz".into()),
@@ -1542,7 +1562,7 @@ mod tests {
         println!("{:?}", cols(&cols_obj.to_zinc()));
 
         let row = Row::new(vec![
-            Val::new(Box::new(Token::DateTime(now))),
+            Val::new(Box::new(Token::DateTime(now, None))),
             Val::new(Box::new(Token::EscapedString("UTC".into()))),
         ]);
 